@@ -1,9 +1,29 @@
 pub fn main() {
     let proto_files = ["./proto/permission.proto"];
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
 
     tonic_build::configure()
-        .type_attribute("Input", "#[derive(serde::Deserialize, serde::Serialize)]")
+        .type_attribute(
+            "Input",
+            "#[derive(serde::Deserialize, serde::Serialize, utoipa::ToSchema)]",
+        )
+        .type_attribute(
+            "PurgeInput",
+            "#[derive(serde::Deserialize, serde::Serialize, utoipa::ToSchema)]",
+        )
+        .type_attribute(
+            "CloneInput",
+            "#[derive(serde::Deserialize, serde::Serialize, utoipa::ToSchema)] #[serde(default)]",
+        )
+        .type_attribute(
+            "Job",
+            "#[derive(serde::Deserialize, serde::Serialize, utoipa::ToSchema)]",
+        )
+        .field_attribute("Input.dry_run", "#[serde(default)]")
+        .field_attribute("Input.backend", "#[serde(default)]")
+        .field_attribute("PurgeInput.dry_run", "#[serde(default)]")
         .build_server(true)
+        .file_descriptor_set_path(out_dir.join("permission_descriptor.bin"))
         .compile(&proto_files, &["."])
         .unwrap_or_else(|e| panic!("protobuf compile error: {e}"));
     println!("cargo:rerun-if-changed={proto_files:?}");
@@ -0,0 +1,237 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+use tracing::{debug, info};
+
+use ory_kratos_client::models::Identity;
+
+use crate::{
+    http::controler::clone_patch,
+    kratos::{KratosClient, PAGE_SIZE},
+    permission::{CloneInput, CloneStrategy, Mode},
+    reconcile::{apply_plan, fetch_known, IdentityPlan, Plan},
+    schema::{validate_patch, Schemas},
+};
+
+///Permission data of an identity as written in an export, one record is written per line.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq)]
+pub struct IdentityRecord {
+    pub id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_admin: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata_public: Option<Value>,
+    ///Only the permission related traits given to the export are kept.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traits: Option<Value>,
+}
+
+impl IdentityRecord {
+    ///Extract the permission data of an identity, only the listed traits are kept.
+    pub fn new(identity: Identity, traits: &[String]) -> Self {
+        let traits = match identity.traits {
+            Some(Value::Object(all)) if !traits.is_empty() => {
+                let kept: Map<String, Value> = all
+                    .into_iter()
+                    .filter(|(key, _)| traits.contains(key))
+                    .collect();
+                Some(Value::Object(kept))
+            }
+            _ => None,
+        };
+        IdentityRecord {
+            id: identity.id,
+            metadata_admin: identity.metadata_admin,
+            metadata_public: identity.metadata_public,
+            traits,
+        }
+    }
+
+    ///Perm_types of the record in every mode, the traits are the exported ones.
+    fn perm_types(&self) -> Vec<String> {
+        let modes = [&self.metadata_admin, &self.metadata_public, &self.traits];
+        let mut perm_types: Vec<String> = modes
+            .into_iter()
+            .filter_map(|data| data.as_ref()?.as_object())
+            .flat_map(|data| data.keys().cloned())
+            .collect();
+        perm_types.sort();
+        perm_types.dedup();
+        perm_types
+    }
+
+    ///Build an identity holding the data of the record.
+    fn identity(&self) -> Identity {
+        let mut identity = Identity::new(
+            self.id.clone(),
+            String::new(),
+            String::new(),
+            self.traits.clone(),
+        );
+        identity.metadata_admin = self.metadata_admin.clone();
+        identity.metadata_public = self.metadata_public.clone();
+        identity
+    }
+}
+
+///Write every identity record in the channel, stop when the receiver is dropped.
+async fn export_to(
+    client: &KratosClient,
+    traits: &[String],
+    sender: &mpsc::Sender<Result<String>>,
+) -> Result<()> {
+    let mut page_token = None;
+    loop {
+        let (identities, next) = client.list_identities(page_token.as_deref()).await?;
+        for identity in identities {
+            let line = serde_json::to_string(&IdentityRecord::new(identity, traits))? + "\n";
+            if sender.send(Ok(line)).await.is_err() {
+                return Ok(());
+            }
+        }
+        if next.is_none() {
+            return Ok(());
+        }
+        page_token = next;
+    }
+}
+
+///Stream the permission data of every identity as ndjson, the identities are fetched from
+///kratos page by page while the stream is consumed.
+pub fn export(
+    client: KratosClient,
+    uuid: String,
+    traits: Vec<String>,
+) -> ReceiverStream<Result<String>> {
+    let (sender, receiver) = mpsc::channel(PAGE_SIZE as usize);
+    tokio::spawn(async move {
+        info!("{uuid}: exporting identities");
+        if let Err(e) = export_to(&client, &traits, &sender).await {
+            let _ = sender
+                .send(Err(e.context(format!("{uuid}: export failed"))))
+                .await;
+            return;
+        }
+        info!("{uuid}: export done");
+    });
+    ReceiverStream::new(receiver)
+}
+
+///Import the permission data of an ndjson export. With the merge strategy the exported
+///resources are added to the existing ones, with the overwrite strategy the exported perm_types
+///replace the existing ones. Every record is checked against the perm_type schemas before any
+///patch is applied and the identities missing from kratos are skipped, without `apply` the
+///patches are only computed.
+pub async fn import(
+    client: &KratosClient,
+    schemas: &Schemas,
+    uuid: &str,
+    content: &str,
+    strategy: CloneStrategy,
+    apply: bool,
+) -> Result<Plan> {
+    let mut identities = Vec::new();
+    let mut unknown = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str::<IdentityRecord>(line)
+            .context(format!("{uuid}: invalid record at line {}", index + 1))?;
+        let Some(target) = fetch_known(client, uuid, &record.id, apply).await? else {
+            unknown.push(record.id);
+            continue;
+        };
+        // the exported traits are imported too
+        let mut payload = CloneInput {
+            source: record.id.clone(),
+            target: record.id.clone(),
+            modes: [Mode::Admin, Mode::Public, Mode::Trait]
+                .map(i32::from)
+                .to_vec(),
+            perm_types: record.perm_types(),
+            ..Default::default()
+        };
+        payload.set_strategy(strategy);
+        let patch = clone_patch(&record.identity(), &target, &payload);
+        if patch.is_empty() {
+            continue;
+        }
+        debug!("{uuid}: import patch for {}: {patch:?}", record.id);
+        validate_patch(schemas, &patch).context(format!("{uuid}: {}", record.id))?;
+        identities.push(IdentityPlan::new(target, patch));
+    }
+    info!("{uuid}: {} identities to import", identities.len());
+    if apply {
+        apply_plan(client, uuid, &mut identities).await;
+    }
+    Ok(Plan {
+        applied: apply,
+        identities,
+        unknown,
+    })
+}
+
+#[cfg(test)]
+mod test_backup {
+    use serde_json::json;
+    use tokio_stream::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_export_import() {
+        let client = KratosClient::default();
+        let lines: Vec<String> = export(client.clone(), "test".to_owned(), Vec::new())
+            .map(|line| line.unwrap())
+            .collect()
+            .await;
+        assert_eq!(lines.len(), 2);
+        let record: IdentityRecord = serde_json::from_str(&lines[0]).unwrap();
+        assert_eq!(record.id, "owner");
+
+        let content = concat!(
+            r#"{"id": "target", "metadata_admin": {"group": ["1"]}}"#,
+            "\n",
+            r#"{"id": "unknown", "metadata_admin": {"group": ["1"]}}"#,
+        );
+        let plan = import(
+            &client,
+            &Schemas::default(),
+            "test",
+            content,
+            CloneStrategy::Merge,
+            false,
+        )
+        .await
+        .unwrap();
+        assert!(!plan.applied);
+        assert_eq!(plan.unknown, vec!["unknown".to_owned()]);
+        assert_eq!(
+            plan.identities[0].patch,
+            vec![json!({"op": "add", "path": "/metadata_admin/group/-", "value": "1"})]
+        );
+
+        // the exported traits are restored
+        let content = r#"{"id": "target", "traits": {"project": {"1": "member"}}}"#;
+        let plan = import(
+            &client,
+            &Schemas::default(),
+            "test",
+            content,
+            CloneStrategy::Merge,
+            false,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            plan.identities[0].patch,
+            vec![
+                json!({"op": "add", "path": "/traits", "value": {}}),
+                json!({"op": "add", "path": "/traits/project", "value": {"1": "member"}}),
+            ]
+        );
+    }
+}
@@ -0,0 +1,121 @@
+use std::{
+    num::NonZeroUsize,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use lru::LruCache;
+use ory_kratos_client::models::Identity;
+
+#[derive(Debug)]
+struct Entries {
+    identities: LruCache<String, (Instant, Identity)>,
+    ///Incremented by every invalidation.
+    generation: u64,
+}
+
+///Least recently used cache of the kratos identities, an identity is kept at most `ttl`.
+#[derive(Debug)]
+pub struct IdentityCache {
+    entries: Mutex<Entries>,
+    ttl: Duration,
+}
+
+impl IdentityCache {
+    pub fn new(capacity: NonZeroUsize, ttl: Duration) -> Self {
+        IdentityCache {
+            entries: Mutex::new(Entries {
+                identities: LruCache::new(capacity),
+                generation: 0,
+            }),
+            ttl,
+        }
+    }
+
+    ///Get a cached identity, the expired identities are evicted.
+    pub fn get(&self, id: &str) -> Option<Identity> {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        if let Some((cached_at, identity)) = entries.identities.get(id) {
+            if cached_at.elapsed() < self.ttl {
+                return Some(identity.clone());
+            }
+            entries.identities.pop(id);
+        }
+        None
+    }
+
+    ///Generation of the cache, to be read before fetching an identity to insert.
+    pub fn generation(&self) -> u64 {
+        self.entries.lock().expect("cache lock poisoned").generation
+    }
+
+    ///Cache an identity fetched at `generation`, it is dropped if an identity was invalidated
+    ///since as the fetch may have read it before its patch.
+    pub fn insert(&self, id: &str, identity: &Identity, generation: u64) {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        if entries.generation == generation {
+            entries
+                .identities
+                .put(id.to_owned(), (Instant::now(), identity.clone()));
+        }
+    }
+
+    pub fn invalidate(&self, id: &str) {
+        let mut entries = self.entries.lock().expect("cache lock poisoned");
+        entries.identities.pop(id);
+        entries.generation += 1;
+    }
+
+    pub fn capacity(&self) -> NonZeroUsize {
+        self.entries
+            .lock()
+            .expect("cache lock poisoned")
+            .identities
+            .cap()
+    }
+
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    ///Tell if the cache has the given capacity and ttl.
+    pub fn has_settings(&self, capacity: NonZeroUsize, ttl: Duration) -> bool {
+        self.ttl == ttl && self.capacity() == capacity
+    }
+}
+
+#[cfg(test)]
+mod test_cache {
+    use super::*;
+
+    fn identity(id: &str) -> Identity {
+        Identity::new(id.to_owned(), "test".to_owned(), "test".to_owned(), None)
+    }
+
+    #[test]
+    fn test_cache() {
+        let cache = IdentityCache::new(NonZeroUsize::new(1).unwrap(), Duration::from_secs(60));
+        cache.insert("1", &identity("1"), 0);
+        assert_eq!(cache.get("1").unwrap().id, "1");
+        cache.insert("2", &identity("2"), 0);
+        assert!(cache.get("1").is_none());
+        cache.invalidate("2");
+        assert!(cache.get("2").is_none());
+
+        let cache = IdentityCache::new(NonZeroUsize::new(1).unwrap(), Duration::ZERO);
+        cache.insert("1", &identity("1"), 0);
+        assert!(cache.get("1").is_none());
+    }
+
+    #[test]
+    fn test_stale_insert() {
+        let cache = IdentityCache::new(NonZeroUsize::new(2).unwrap(), Duration::from_secs(60));
+        let generation = cache.generation();
+        // a patch invalidates the identity while it is fetched
+        cache.invalidate("1");
+        cache.insert("1", &identity("1"), generation);
+        assert!(cache.get("1").is_none());
+        cache.insert("1", &identity("1"), cache.generation());
+        assert!(cache.get("1").is_some());
+    }
+}
@@ -0,0 +1,349 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use arc_swap::ArcSwap;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+use tokio::io::{stdout, AsyncWriteExt};
+use tokio_stream::StreamExt;
+use tracing::warn;
+
+use crate::{
+    backup::{export, import},
+    config::{IamConfig, CONFIG_FALLBACK},
+    event::{EventPublisher, PermissionEvent},
+    http::controler::{dry_run, kratos, mode_data, PermissionState},
+    kratos::KratosClient,
+    permission::{CloneStrategy, Input, Mode},
+    shutdown::Tasks,
+};
+
+///Uuid used in the logs of the commands run from the cli.
+const CLI_UUID: &str = "cli";
+
+///Iam api, without subcommand the servers are launched.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+pub struct Cli {
+    ///Paths of the config files separated by commas, the later files override the earlier ones.
+    #[arg(
+        long,
+        env = "CONFIG",
+        default_value = CONFIG_FALLBACK,
+        value_delimiter = ',',
+        global = true
+    )]
+    pub config: Vec<String>,
+    ///Name of the kratos backend the commands run against, the default one when omitted.
+    #[arg(long, global = true)]
+    pub backend: Option<String>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    ///Launch the http and grpc servers.
+    Serve,
+    ///Add or replace a permission of an identity.
+    Grant {
+        #[command(flatten)]
+        target: Target,
+        ///The json value of the permission, when omitted the resource is added to a list.
+        #[arg(long, default_value = "null")]
+        value: String,
+        ///Replace the existing value instead of adding it.
+        #[arg(long)]
+        replace: bool,
+        ///Print the patch that would be applied without modifying the identity.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    ///Remove a permission from an identity.
+    Revoke {
+        #[command(flatten)]
+        target: Target,
+    },
+    ///Print the permissions of an identity.
+    Show {
+        ///The id of the identity.
+        #[arg(long)]
+        id: String,
+        ///Only print the data of this mode.
+        #[arg(long, value_enum)]
+        mode: Option<ModeArg>,
+    },
+    ///Check that an identity has a permission, exit with an error if it does not.
+    Check {
+        #[command(flatten)]
+        target: Target,
+        ///The json value the permission must have.
+        #[arg(long)]
+        value: Option<String>,
+    },
+    ///Write the permission data of every identity to the standard output as ndjson.
+    Export {
+        ///Permission related traits to export.
+        #[arg(long, value_delimiter = ',')]
+        traits: Vec<String>,
+    },
+    ///Import the permission data of an ndjson export.
+    Import {
+        ///Path of the export file.
+        file: String,
+        ///Replace the exported perm_types instead of merging them with the existing ones.
+        #[arg(long)]
+        overwrite: bool,
+        ///Print the patches that would be applied without modifying the identities.
+        #[arg(long)]
+        dry_run: bool,
+    },
+    ///Load the config file and report the errors.
+    ValidateConfig,
+}
+
+///Permission targeted by a command.
+#[derive(Args, Debug)]
+pub struct Target {
+    ///The id of the identity.
+    #[arg(long)]
+    id: String,
+    #[arg(long, value_enum, default_value = "admin")]
+    mode: ModeArg,
+    #[arg(long)]
+    perm_type: String,
+    #[arg(long)]
+    resource: String,
+}
+
+///Cli representation of the identity data modes.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum ModeArg {
+    Admin,
+    Public,
+    Trait,
+}
+
+impl From<ModeArg> for Mode {
+    fn from(mode: ModeArg) -> Self {
+        match mode {
+            ModeArg::Admin => Mode::Admin,
+            ModeArg::Public => Mode::Public,
+            ModeArg::Trait => Mode::Trait,
+        }
+    }
+}
+
+impl Target {
+    fn input(&self, value: String) -> Input {
+        let mut input = Input {
+            id: self.id.clone(),
+            perm_type: self.perm_type.clone(),
+            resource: self.resource.clone(),
+            value,
+            ..Default::default()
+        };
+        input.set_mode(self.mode.into());
+        input
+    }
+}
+
+fn print(value: &impl serde::Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+///Run an admin command against kratos, the changes are published to the configured webhooks
+///before returning.
+pub async fn run(config_paths: &[String], backend: Option<&str>, command: Command) -> Result<()> {
+    let config = IamConfig::load(config_paths).await?;
+    if let Command::ValidateConfig = command {
+        // the config is checked as if iam was served
+        config.validate_listeners().await?;
+        println!("{} is valid", config_paths.join(", "));
+        return Ok(());
+    }
+    let timeout = Duration::from_secs(config.shutdown.timeout);
+    let shared = Arc::new(ArcSwap::from_pointee(config));
+    let config = shared.load_full();
+    let tasks = Tasks::default();
+    let events = EventPublisher::new(shared, tasks.clone());
+    let client = config.client(backend)?;
+    execute(&config, client, &events, command).await?;
+    if tokio::time::timeout(timeout, tasks.idle()).await.is_err() {
+        warn!("the events still not delivered after {timeout:?} are dropped");
+    }
+    Ok(())
+}
+
+///Execute a command with the kratos client, the changes are published with `events`.
+async fn execute(
+    config: &IamConfig,
+    client: &KratosClient,
+    events: &EventPublisher,
+    command: Command,
+) -> Result<()> {
+    match command {
+        Command::Grant {
+            target,
+            value,
+            replace,
+            dry_run: true,
+        } => {
+            let op = if replace { "replace" } else { "add" };
+            print(&dry_run(client, &config.schemas, CLI_UUID, &target.input(value), op).await?)
+        }
+        Command::Grant {
+            target,
+            value,
+            replace,
+            dry_run: false,
+        } => {
+            let op = if replace { "replace" } else { "add" };
+            let input = target.input(value);
+            let event = PermissionEvent::new(CLI_UUID, &input, op);
+            let state = kratos(client, &config.schemas, CLI_UUID, input, op).await?;
+            events.publish(event.with_value(state.value.clone())).await;
+            print(&state)
+        }
+        Command::Revoke { target } => {
+            let input = target.input("null".to_owned());
+            let event = PermissionEvent::new(CLI_UUID, &input, "remove");
+            let state = kratos(client, &config.schemas, CLI_UUID, input, "remove").await?;
+            events.publish(event.with_value(state.value.clone())).await;
+            print(&state)
+        }
+        Command::Show { id, mode } => {
+            let identity = client.get_identity(&id).await?;
+            let modes = match mode {
+                Some(mode) => vec![mode.into()],
+                None => vec![Mode::Admin, Mode::Public, Mode::Trait],
+            };
+            let mut data = json!({});
+            for mode in modes {
+                let (root, meta) = mode_data(&identity, mode);
+                data[root] = meta.clone().unwrap_or_default();
+            }
+            print(&data)
+        }
+        Command::Check { target, value } => {
+            let identity = client.get_identity(&target.id).await?;
+            let state = PermissionState::new(&identity, &target.input(String::new()));
+            let expected = match value {
+                Some(value) => Some(serde_json::from_str::<Value>(&value)?),
+                None => None,
+            };
+            match expected {
+                _ if state.value.is_null() => bail!(
+                    "{} does not have {}/{}",
+                    target.id,
+                    target.perm_type,
+                    target.resource
+                ),
+                Some(expected) if expected != state.value => bail!(
+                    "{} has {}/{} with value {} instead of {expected}",
+                    target.id,
+                    target.perm_type,
+                    target.resource,
+                    state.value
+                ),
+                _ => print(&state),
+            }
+        }
+        Command::Export { traits } => {
+            let mut lines = export(client.clone(), CLI_UUID.to_owned(), traits);
+            let mut out = stdout();
+            while let Some(line) = lines.next().await {
+                out.write_all(line?.as_bytes()).await?;
+            }
+            out.flush().await?;
+            Ok(())
+        }
+        Command::Import {
+            file,
+            overwrite,
+            dry_run,
+        } => {
+            let content = tokio::fs::read_to_string(&file).await?;
+            let strategy = match overwrite {
+                true => CloneStrategy::Overwrite,
+                false => CloneStrategy::Merge,
+            };
+            let plan = import(
+                client,
+                &config.schemas,
+                CLI_UUID,
+                &content,
+                strategy,
+                !dry_run,
+            )
+            .await?;
+            events.publish_plan(CLI_UUID, &plan).await;
+            print(&plan)
+        }
+        Command::Serve | Command::ValidateConfig => {
+            unreachable!("served by main and validated before the client is selected")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_cli {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+        let cli = Cli::parse_from([
+            "iam",
+            "grant",
+            "--id",
+            "1",
+            "--perm-type",
+            "project",
+            "--resource",
+            "222",
+            "--value",
+            "\"owner\"",
+        ]);
+        let Some(Command::Grant { target, value, .. }) = cli.command else {
+            panic!("expected grant command")
+        };
+        let input = target.input(value);
+        assert_eq!(input.mode(), Mode::Admin);
+        assert_eq!(input.value, "\"owner\"");
+    }
+
+    #[tokio::test]
+    async fn test_grant_event() {
+        let events = EventPublisher::new(Arc::default(), Tasks::default());
+        let mut watcher = events.subscribe();
+        let command = Command::Grant {
+            target: Target {
+                id: "1".to_owned(),
+                mode: ModeArg::Admin,
+                perm_type: "project".to_owned(),
+                resource: "222".to_owned(),
+            },
+            value: "\"owner\"".to_owned(),
+            replace: false,
+            dry_run: false,
+        };
+        let config = IamConfig::default();
+        execute(&config, &KratosClient::default(), &events, command)
+            .await
+            .unwrap();
+        let event = watcher.recv().await.unwrap();
+        assert_eq!((&*event.op, &*event.resource), ("add", "222"));
+        assert_eq!(event.value, json!("owner"));
+    }
+
+    #[tokio::test]
+    async fn test_validate_config() {
+        run(&[CONFIG_FALLBACK.to_owned()], None, Command::ValidateConfig)
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,445 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{bail, Result};
+use futures::future::join_all;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio::{
+    fs::OpenOptions,
+    io::AsyncWriteExt,
+    sync::{
+        broadcast,
+        mpsc::{self, error::TrySendError},
+        Mutex,
+    },
+};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use ory_kratos_client::models::Identity;
+
+use crate::{
+    config::{Events, SharedConfig},
+    http::controler::unescape_segment,
+    permission::Input,
+    reconcile::{root_mode, Plan},
+    shutdown::{TaskGuard, Tasks},
+};
+
+///Representation of a change applied to the permissions of an identity.
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct PermissionEvent {
+    ///The uuid of the request that made the change.
+    #[serde(skip)]
+    pub uuid: String,
+    pub id: String,
+    pub mode: String,
+    pub perm_type: String,
+    pub resource: String,
+    pub op: String,
+    ///The value at the modified path once the change is applied.
+    pub value: Value,
+}
+
+impl PermissionEvent {
+    pub fn new(uuid: &str, payload: &Input, op: &str) -> Self {
+        PermissionEvent {
+            uuid: uuid.to_owned(),
+            id: payload.id.clone(),
+            mode: payload.mode().as_str_name().to_owned(),
+            perm_type: payload.perm_type.clone(),
+            resource: payload.resource.clone(),
+            op: op.to_owned(),
+            value: Value::Null,
+        }
+    }
+
+    ///Create the event of a json patch operation applied to the identity data `doc`, the
+    ///resource is empty for an operation on a whole perm_type, it is the added or removed element
+    ///for an operation on a list and the creation of a mode has no event.
+    pub fn from_patch(uuid: &str, id: &str, doc: &Value, patch: &Value) -> Option<Self> {
+        let pointer = patch["path"].as_str()?;
+        let mut path = pointer.split('/').skip(1);
+        let mode = root_mode(path.next()?).ok()?;
+        let perm_type = path.next()?;
+        let mut resource = unescape_segment(path.next().unwrap_or_default());
+        let (parent, _) = pointer.rsplit_once('/')?;
+        if let Some(Value::Array(_)) = doc.pointer(parent) {
+            // the resources of a list are its elements
+            let element = patch.get("value").or_else(|| doc.pointer(pointer));
+            resource = match element {
+                Some(Value::String(element)) => element.clone(),
+                Some(element) => element.to_string(),
+                None => resource,
+            };
+        }
+        Some(PermissionEvent {
+            uuid: uuid.to_owned(),
+            id: id.to_owned(),
+            mode: mode.as_str_name().to_owned(),
+            perm_type: unescape_segment(perm_type),
+            resource,
+            op: patch["op"].as_str()?.to_owned(),
+            value: patch.get("value").cloned().unwrap_or_default(),
+        })
+    }
+
+    ///Set the value of the modified path.
+    pub fn with_value(mut self, value: Value) -> Self {
+        self.value = value;
+        self
+    }
+
+    ///Format the event following the CloudEvents json specification, each event gets its own id
+    ///and the uuid of the request is given in the `requestid` extension.
+    pub fn to_cloud_event(&self) -> Value {
+        json!({
+            "specversion": "1.0",
+            "id": Uuid::new_v4().to_string(),
+            "requestid": self.uuid,
+            "source": "iam",
+            "type": "io.w6d.iam.permission.".to_owned() + &self.op,
+            "subject": self.id,
+            "time": chrono::Utc::now().to_rfc3339(),
+            "datacontenttype": "application/json",
+            "data": self,
+        })
+    }
+}
+
+///Number of events kept for the slow watchers before they start missing events.
+const WATCH_CAPACITY: usize = 1024;
+
+///Handle used to publish the permission events, the events are queued and sent to the webhooks
+///configured in the `events` section of the config by a fixed set of delivery workers, and
+///broadcasted to the watchers.
+#[derive(Clone, Debug)]
+pub struct EventPublisher {
+    config: SharedConfig,
+    sender: mpsc::Sender<(PermissionEvent, TaskGuard)>,
+    watchers: broadcast::Sender<PermissionEvent>,
+    tasks: Tasks,
+}
+
+impl EventPublisher {
+    ///Create the publisher and spawn the delivery workers, the queued events are tracked in
+    ///`tasks` until they are delivered.
+    pub fn new(config: SharedConfig, tasks: Tasks) -> Self {
+        let events = config.load().events.clone();
+        let (sender, receiver) = mpsc::channel(events.queue.max(1));
+        let receiver = Arc::new(Mutex::new(receiver));
+        let client = reqwest::Client::new();
+        for _ in 0..events.workers.max(1) {
+            tokio::spawn(work(config.clone(), client.clone(), receiver.clone()));
+        }
+        let (watchers, _) = broadcast::channel(WATCH_CAPACITY);
+        EventPublisher {
+            config,
+            sender,
+            watchers,
+            tasks,
+        }
+    }
+
+    ///Queue an event for delivery, when the queue is full the event is written to the dead
+    ///letter file instead.
+    pub async fn publish(&self, event: PermissionEvent) {
+        // sending only fails when nobody is watching
+        let _ = self.watchers.send(event.clone());
+        let events = self.config.load().events.clone();
+        if events.webhooks.is_empty() {
+            return;
+        }
+        let event = match self.sender.try_send((event, self.tasks.track())) {
+            Ok(()) => return,
+            Err(TrySendError::Full((event, _))) => {
+                warn!(
+                    "{}: event queue full, the event is not delivered",
+                    event.uuid
+                );
+                event
+            }
+            Err(TrySendError::Closed((event, _))) => {
+                error!("{}: event delivery workers stopped", event.uuid);
+                event
+            }
+        };
+        let event = event.to_cloud_event();
+        for url in &events.webhooks {
+            give_up(&events, url, &event).await;
+        }
+    }
+
+    ///Publish the events of the json patch applied to an identity, the identity is the one the
+    ///patch was built from.
+    pub async fn publish_patch(&self, uuid: &str, identity: &Identity, patch: &[Value]) {
+        // the operations are replayed to know the elements removed from the lists
+        let mut doc = serde_json::to_value(identity).unwrap_or_default();
+        for operation in patch {
+            let event = PermissionEvent::from_patch(uuid, &identity.id, &doc, operation);
+            let operation = Value::Array(vec![operation.clone()]);
+            if let Ok(operation) = serde_json::from_value::<json_patch::Patch>(operation) {
+                let _ = json_patch::patch(&mut doc, &operation);
+            }
+            if let Some(event) = event {
+                self.publish(event).await;
+            }
+        }
+    }
+
+    ///Publish the events of the patches of a plan that were applied.
+    pub async fn publish_plan(&self, uuid: &str, plan: &Plan) {
+        for identity in plan.identities.iter().filter(|identity| identity.applied) {
+            self.publish_patch(uuid, &identity.identity, &identity.patch)
+                .await;
+        }
+    }
+
+    ///Subscribe to the events published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<PermissionEvent> {
+        self.watchers.subscribe()
+    }
+}
+
+///Delivery worker, take the queued events one by one and deliver each of them to the
+///configured webhooks.
+async fn work(
+    config: SharedConfig,
+    client: reqwest::Client,
+    receiver: Arc<Mutex<mpsc::Receiver<(PermissionEvent, TaskGuard)>>>,
+) {
+    loop {
+        // the guard is dropped once the event is delivered
+        let Some((event, _guard)) = receiver.lock().await.recv().await else {
+            return;
+        };
+        let events = config.load().events.clone();
+        let event = event.to_cloud_event();
+        let deliveries = events
+            .webhooks
+            .iter()
+            .map(|url| deliver(client.clone(), events.clone(), url.clone(), event.clone()));
+        join_all(deliveries).await;
+    }
+}
+
+///Post an event to a webhook, retrying with an exponential backoff. The event is written to the
+///dead letter file when every attempt failed.
+async fn deliver(client: reqwest::Client, events: Events, url: String, event: Value) {
+    let mut backoff = Duration::from_millis(events.backoff);
+    for attempt in 0..=events.retries {
+        match post(&client, &url, &event).await {
+            Ok(()) => {
+                debug!("{}: event delivered to {url}", event["requestid"]);
+                return;
+            }
+            Err(e) => warn!(
+                "{}: failed to deliver event to {url} (attempt {}): {e}",
+                event["requestid"],
+                attempt + 1
+            ),
+        }
+        if attempt < events.retries {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    give_up(&events, &url, &event).await;
+}
+
+///Give up the delivery of an event to a webhook, it is written to the dead letter file if set.
+async fn give_up(events: &Events, url: &str, event: &Value) {
+    error!("{}: giving up delivery to {url}", event["requestid"]);
+    if let Some(path) = &events.dead_letter {
+        if let Err(e) = dead_letter(path, url, event).await {
+            error!("{}: failed to write dead letter: {e}", event["requestid"]);
+        }
+    }
+}
+///Send the event to the webhook.
+async fn post(client: &reqwest::Client, url: &str, event: &Value) -> Result<()> {
+    let resp = client
+        .post(url)
+        .header(
+            reqwest::header::CONTENT_TYPE,
+            "application/cloudevents+json",
+        )
+        .timeout(Duration::from_secs(10))
+        .body(event.to_string())
+        .send()
+        .await?;
+    if !resp.status().is_success() {
+        bail!("webhook responded with {}", resp.status());
+    }
+    Ok(())
+}
+
+///Append the undelivered event to the dead letter file.
+async fn dead_letter(path: &str, url: &str, event: &Value) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let line = json!({"url": url, "event": event}).to_string() + "\n";
+    file.write_all(line.as_bytes()).await?;
+    info!(
+        "{}: event written to dead letter {path}",
+        event["requestid"]
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_event {
+    use arc_swap::ArcSwap;
+    use axum::{extract::State, http::StatusCode, routing::post as post_route, Json, Router};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{config::IamConfig, permission::Mode};
+
+    ///Launch a webhook stub forwarding the received events in a channel.
+    async fn stub(status: StatusCode) -> (String, mpsc::UnboundedReceiver<Value>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let app = Router::new()
+            .route(
+                "/",
+                post_route(
+                    move |State(sender): State<mpsc::UnboundedSender<Value>>,
+                          Json(event): Json<Value>| async move {
+                        sender.send(event).unwrap();
+                        status
+                    },
+                ),
+            )
+            .with_state(sender);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async { axum::serve(listener, app).await });
+        (url, receiver)
+    }
+
+    fn event() -> Value {
+        let payload = Input {
+            id: "1".to_owned(),
+            perm_type: "project".to_owned(),
+            resource: "222".to_owned(),
+            ..Default::default()
+        };
+        PermissionEvent::new("test", &payload, "add")
+            .with_value(json!("owner"))
+            .to_cloud_event()
+    }
+
+    #[tokio::test]
+    async fn test_deliver() {
+        let (url, mut receiver) = stub(StatusCode::OK).await;
+        deliver(reqwest::Client::new(), Events::default(), url, event()).await;
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event["type"], "io.w6d.iam.permission.add");
+        assert_eq!(event["data"]["value"], "owner");
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let (url, mut receiver) = stub(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead_letter.ndjson");
+        let events = Events {
+            retries: 1,
+            backoff: 1,
+            dead_letter: Some(path.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        deliver(reqwest::Client::new(), events, url.clone(), event()).await;
+        assert!(receiver.recv().await.is_some());
+        assert!(receiver.recv().await.is_some());
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        let line: Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(line["url"], url);
+        assert_eq!(line["event"]["subject"], "1");
+    }
+
+    #[test]
+    fn test_from_patch() {
+        let doc = json!({"traits": {"group": ["222"]}});
+        let patch = json!({"op": "add", "path": "/metadata_admin/project/2~122", "value": "owner"});
+        let event = PermissionEvent::from_patch("test", "1", &doc, &patch).unwrap();
+        assert_eq!(event.mode, Mode::Admin.as_str_name());
+        assert_eq!(event.perm_type, "project");
+        assert_eq!(event.resource, "2/22");
+        assert_eq!(event.op, "add");
+        assert_eq!(event.value, "owner");
+        let patch = json!({"op": "remove", "path": "/traits/group/0"});
+        let event = PermissionEvent::from_patch("test", "1", &doc, &patch).unwrap();
+        assert_eq!(event.resource, "222");
+        assert_eq!(event.value, Value::Null);
+        let patch = json!({"op": "add", "path": "/traits/group/-", "value": "333"});
+        let event = PermissionEvent::from_patch("test", "1", &doc, &patch).unwrap();
+        assert_eq!(event.resource, "333");
+        let patch = json!({"op": "replace", "path": "/traits/group", "value": ["1"]});
+        let event = PermissionEvent::from_patch("test", "1", &doc, &patch).unwrap();
+        assert_eq!(event.resource, "");
+        let patch = json!({"op": "add", "path": "/metadata_public", "value": {}});
+        assert!(PermissionEvent::from_patch("test", "1", &doc, &patch).is_none());
+    }
+
+    #[test]
+    fn test_cloud_event_id() {
+        let first = event();
+        let second = event();
+        assert_ne!(first["id"], second["id"]);
+        assert_eq!(first["requestid"], "test");
+    }
+
+    #[tokio::test]
+    async fn test_publish_patch() {
+        let publisher = EventPublisher::new(Arc::default(), Tasks::default());
+        let mut watcher = publisher.subscribe();
+        let mut identity = Identity::new("1".to_owned(), String::new(), String::new(), None);
+        identity.metadata_admin = Some(json!({"group": ["1", "2", "3"]}));
+        // the elements are removed from the end
+        let patch = [
+            json!({"op": "remove", "path": "/metadata_admin/group/2"}),
+            json!({"op": "remove", "path": "/metadata_admin/group/0"}),
+        ];
+        publisher.publish_patch("test", &identity, &patch).await;
+        assert_eq!(watcher.recv().await.unwrap().resource, "3");
+        assert_eq!(watcher.recv().await.unwrap().resource, "1");
+    }
+
+    #[tokio::test]
+    async fn test_queue_full() {
+        let (url, _receiver) = stub(StatusCode::INTERNAL_SERVER_ERROR).await;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("dead_letter.ndjson");
+        let config = IamConfig {
+            events: Events {
+                webhooks: vec![url],
+                backoff: 60_000,
+                dead_letter: Some(path.to_string_lossy().into_owned()),
+                queue: 1,
+                workers: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let config = Arc::new(ArcSwap::from_pointee(config));
+        let publisher = EventPublisher::new(config, Tasks::default());
+        let payload = Input {
+            id: "1".to_owned(),
+            ..Default::default()
+        };
+        // the worker does not run before the second event, it overflows the queue
+        publisher
+            .publish(PermissionEvent::new("first", &payload, "add"))
+            .await;
+        publisher
+            .publish(PermissionEvent::new("second", &payload, "add"))
+            .await;
+        let content = tokio::fs::read_to_string(&path).await.unwrap();
+        let line: Value = serde_json::from_str(content.trim()).unwrap();
+        assert_eq!(line["event"]["requestid"], "second");
+    }
+}
@@ -1,71 +1,239 @@
-use std::sync::Arc;
+use std::pin::Pin;
 
-use tokio::sync::RwLock;
-use tonic::{async_trait, Request, Response as TonicResponse, Status};
+use futures::{future::ready, stream, StreamExt};
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream,
+};
+use tonic::{
+    async_trait, metadata::MetadataMap, Extensions, Request, Response as TonicResponse, Status,
+};
 use tower_http::request_id::RequestId;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::{
-    config::IamConfig,
-    http::controler::kratos,
-    permission::{iam_server::Iam, Input, Reply},
+    config::{IamConfig, SharedConfig},
+    event::{EventPublisher, PermissionEvent},
+    http::{
+        backend::{Backend, BACKEND_HEADER},
+        controler::{clone_permissions, dry_run, kratos, purge_resource, PermissionState},
+    },
+    job::JobRegistry,
+    kratos::{IdentityNotFound, KratosBusy, KratosClient, KratosUnavailable, UnknownBackend},
+    limit::grpc_identity,
+    permission::{
+        iam_server::Iam, CloneInput, Input, Job, JobId, Mode, PermissionChange, PurgeInput, Reply,
+        WatchInput,
+    },
+    schema::{SchemaError, Schemas},
+    shutdown::ShutdownCoordinator,
 };
 
 ///Representation of the router, it implements the trait `PermissionSrv`
 ///wich is generated by tonic-build, the member function represente the diferente route.
 pub struct MyIam {
-    pub config: Arc<RwLock<IamConfig>>,
+    pub config: SharedConfig,
+    pub jobs: JobRegistry,
+    pub events: EventPublisher,
+    pub shutdown: ShutdownCoordinator,
+}
+
+///Get the uuid assigned to the request by the request id layer.
+fn request_uuid(ext: &Extensions) -> Result<&str, Status> {
+    match ext.get::<RequestId>() {
+        Some(uuid) => Ok(uuid
+            .header_value()
+            .to_str()
+            .expect("the uuid should be a valide string")),
+        None => Err(Status::internal("No uuid is assigned to this request!")),
+    }
+}
+
+///Get the backend selected by the payload or else by the backend metadata of the request, a peer
+///bound to a backend can only select it.
+fn request_backend(
+    config: &IamConfig,
+    metadata: &MetadataMap,
+    ext: &Extensions,
+    payload: &str,
+) -> Result<Option<String>, Status> {
+    let requested = match metadata.get(BACKEND_HEADER) {
+        Some(backend) => Some(
+            backend
+                .to_str()
+                .map_err(|e| {
+                    Status::invalid_argument(format!("invalid {BACKEND_HEADER} metadata: {e}"))
+                })?
+                .to_owned(),
+        ),
+        None => None,
+    };
+    let caller = grpc_identity(ext);
+    Backend::new(requested, caller.as_deref(), config)
+        .select(payload)
+        .map_err(|e| Status::permission_denied(e.to_string()))
+}
+
+///Convert an error of the controller to a grpc status.
+fn error_status(e: anyhow::Error) -> Status {
+    if let Some(schema_error) = e.downcast_ref::<SchemaError>() {
+        return Status::invalid_argument(schema_error.to_string());
+    }
+    if let Some(unavailable) = e.downcast_ref::<KratosUnavailable>() {
+        return Status::unavailable(unavailable.to_string());
+    }
+    if let Some(busy) = e.downcast_ref::<KratosBusy>() {
+        return Status::resource_exhausted(busy.to_string());
+    }
+    if let Some(unknown) = e.downcast_ref::<UnknownBackend>() {
+        return Status::invalid_argument(unknown.to_string());
+    }
+    if let Some(not_found) = e.downcast_ref::<IdentityNotFound>() {
+        return Status::not_found(not_found.to_string());
+    }
+    Status::internal(e.to_string())
+}
+
+///Run the instruction (op) in dry run mode and put the generated patch and preview in the reply.
+async fn dry_run_reply(
+    client: &KratosClient,
+    schemas: &Schemas,
+    uuid: &str,
+    payload: &Input,
+    op: &str,
+) -> Result<TonicResponse<Reply>, Status> {
+    let result = match dry_run(client, schemas, uuid, payload, op).await {
+        Ok(result) => result,
+        Err(e) => {
+            error!("failed to build patch: {e}");
+            return Err(error_status(e));
+        }
+    };
+    let patch =
+        serde_json::to_string(&result.patch).map_err(|e| Status::internal(e.to_string()))?;
+    Ok(TonicResponse::new(Reply {
+        patch,
+        preview: result.preview.to_string(),
+        ..Default::default()
+    }))
+}
+
+///Put the state of the modified permission in the reply.
+fn state_reply(state: PermissionState) -> TonicResponse<Reply> {
+    TonicResponse::new(Reply {
+        id: state.id,
+        value: state.value.to_string(),
+        permissions: state.permissions.to_string(),
+        ..Default::default()
+    })
+}
+
+///Convert a published event to its grpc representation if it matches the filter.
+fn watched_change(filter: &WatchInput, event: PermissionEvent) -> Option<PermissionChange> {
+    if (!filter.id.is_empty() && filter.id != event.id)
+        || (!filter.perm_type.is_empty() && filter.perm_type != event.perm_type)
+    {
+        return None;
+    }
+    let mode = Mode::from_str_name(&event.mode).unwrap_or(Mode::Admin);
+    Some(PermissionChange {
+        id: event.id,
+        mode: mode.into(),
+        perm_type: event.perm_type,
+        resource: event.resource,
+        op: event.op,
+        value: event.value.to_string(),
+    })
+}
+
+///Stream the changes received by a watcher that match the filter, the stream ends with a data loss
+///error once the watcher lagged behind and missed changes, and with an unavailable error once the
+///servers are drained so the shutdown does not wait for the watchers.
+fn watch_stream(
+    uuid: String,
+    filter: WatchInput,
+    receiver: broadcast::Receiver<PermissionEvent>,
+    shutdown: ShutdownCoordinator,
+) -> impl Stream<Item = Result<PermissionChange, Status>> {
+    let drained = stream::once(async move {
+        shutdown.drain_deadline().await;
+        Err(Status::unavailable(
+            "the server is shutting down, the watch must be restarted",
+        ))
+    });
+    let changes = BroadcastStream::new(receiver).map(move |event| match event {
+        Ok(event) => Ok(watched_change(&filter, event)),
+        Err(BroadcastStreamRecvError::Lagged(skipped)) => {
+            warn!("{uuid}: watcher too slow, {skipped} changes skipped");
+            Err(Status::data_loss(format!(
+                "{skipped} changes skipped, the watch must be restarted"
+            )))
+        }
+    });
+    stream::select(changes, drained)
+        .scan(false, |ended, change| {
+            if *ended {
+                return ready(None);
+            }
+            *ended = change.is_err();
+            ready(Some(change))
+        })
+        .filter_map(|change| ready(change.transpose()))
 }
 
 #[async_trait]
 impl Iam for MyIam {
+    type WatchPermissionsStream =
+        Pin<Box<dyn Stream<Item = Result<PermissionChange, Status>> + Send + 'static>>;
+
     ///Grpc route to add an identity field.
     async fn add_permission(&self, req: Request<Input>) -> Result<TonicResponse<Reply>, Status> {
-        let (_, ext, payload) = req.into_parts();
-        let uuid = match ext.get::<RequestId>() {
-            Some(uuid) => uuid
-                .header_value()
-                .to_str()
-                .expect("the uuid should be a valide string"),
-            None => return Err(Status::internal("No uuid is assigned to this request!")),
-        };
+        let (metadata, ext, payload) = req.into_parts();
+        let uuid = request_uuid(&ext)?;
         info!("{uuid}: adding data to identity");
-        let config = self.config.read().await;
-        let Some(client) = &config.kratos.client else {
-            return Err(Status::internal(format!(
-                "{uuid}: Kratos client not initialized"
-            )));
-        };
-        let resp = kratos(client, uuid, payload, "add").await;
-        if let Err(e) = resp {
-            error!("failed to apply patch: {e}");
-            return Err(Status::internal(e.to_string()));
+        let config = self.config.load_full();
+        let backend = request_backend(&config, &metadata, &ext, &payload.backend)?;
+        let client = config.client(backend.as_deref()).map_err(error_status)?;
+        if payload.dry_run {
+            return dry_run_reply(client, &config.schemas, uuid, &payload, "add").await;
+        }
+        let event = PermissionEvent::new(uuid, &payload, "add");
+        match kratos(client, &config.schemas, uuid, payload, "add").await {
+            Ok(state) => {
+                self.events
+                    .publish(event.with_value(state.value.clone()))
+                    .await;
+                Ok(state_reply(state))
+            }
+            Err(e) => {
+                error!("failed to apply patch: {e}");
+                Err(error_status(e))
+            }
         }
-        Ok(TonicResponse::new(Reply {}))
     }
 
     ///Grpc route to remove an identity field.
     async fn remove_permission(&self, req: Request<Input>) -> Result<TonicResponse<Reply>, Status> {
-        let (_, ext, payload) = req.into_parts();
-        let uuid = match ext.get::<RequestId>() {
-            Some(uuid) => uuid
-                .header_value()
-                .to_str()
-                .expect("the uuid should be a valide string"),
-            None => return Err(Status::internal("No uuid is assigned to this request!")),
-        };
+        let (metadata, ext, payload) = req.into_parts();
+        let uuid = request_uuid(&ext)?;
         info!("{uuid}: removing data to identity");
-        let config = self.config.read().await;
-        let Some(client) = &config.kratos.client else {
-            return Err(Status::internal(format!(
-                "{uuid}: Kratos client not initialized"
-            )));
-        };
-        let resp = kratos(client, uuid, payload, "remove").await;
-        if let Err(e) = resp {
-            return Err(Status::internal(e.to_string()));
+        let config = self.config.load_full();
+        let backend = request_backend(&config, &metadata, &ext, &payload.backend)?;
+        let client = config.client(backend.as_deref()).map_err(error_status)?;
+        if payload.dry_run {
+            return dry_run_reply(client, &config.schemas, uuid, &payload, "remove").await;
+        }
+        let event = PermissionEvent::new(uuid, &payload, "remove");
+        match kratos(client, &config.schemas, uuid, payload, "remove").await {
+            Ok(state) => {
+                self.events
+                    .publish(event.with_value(state.value.clone()))
+                    .await;
+                Ok(state_reply(state))
+            }
+            Err(e) => Err(error_status(e)),
         }
-        Ok(TonicResponse::new(Reply {}))
     }
 
     ///Grpc route to replace an identity field.
@@ -73,25 +241,243 @@ impl Iam for MyIam {
         &self,
         req: Request<Input>,
     ) -> Result<TonicResponse<Reply>, Status> {
-        let (_, ext, payload) = req.into_parts();
-        let uuid = match ext.get::<RequestId>() {
-            Some(uuid) => uuid
-                .header_value()
-                .to_str()
-                .expect("the uuid should be a valide string"),
-            None => return Err(Status::internal("No uuid is assigned to this request!")),
-        };
+        let (metadata, ext, payload) = req.into_parts();
+        let uuid = request_uuid(&ext)?;
         info!("{uuid}: replacing data in identity");
-        let config = self.config.read().await;
-        let Some(client) = &config.kratos.client else {
-            return Err(Status::internal(format!(
-                "{uuid}: Kratos client not initialized"
-            )));
+        let config = self.config.load_full();
+        let backend = request_backend(&config, &metadata, &ext, &payload.backend)?;
+        let client = config.client(backend.as_deref()).map_err(error_status)?;
+        if payload.dry_run {
+            return dry_run_reply(client, &config.schemas, uuid, &payload, "replace").await;
+        }
+        let event = PermissionEvent::new(uuid, &payload, "replace");
+        match kratos(client, &config.schemas, uuid, payload, "replace").await {
+            Ok(state) => {
+                self.events
+                    .publish(event.with_value(state.value.clone()))
+                    .await;
+                Ok(state_reply(state))
+            }
+            Err(e) => Err(error_status(e)),
+        }
+    }
+
+    ///Grpc route to remove a resource from all the identities in a background job.
+    async fn purge_resource(&self, req: Request<PurgeInput>) -> Result<TonicResponse<Job>, Status> {
+        let (metadata, ext, payload) = req.into_parts();
+        let uuid = request_uuid(&ext)?;
+        info!("{uuid}: launching resource purge");
+        let config = self.config.load_full();
+        let backend = request_backend(&config, &metadata, &ext, "")?;
+        let client = config
+            .client(backend.as_deref())
+            .map_err(error_status)?
+            .clone();
+        let job = self.jobs.create(payload.dry_run).await;
+        info!("{uuid}: purge job {}", job.id);
+        let task = self.shutdown.tasks.track();
+        let purge = purge_resource(
+            client,
+            self.jobs.clone(),
+            self.events.clone(),
+            job.id.clone(),
+            payload,
+        );
+        tokio::spawn(async move {
+            purge.await;
+            drop(task);
+        });
+        Ok(TonicResponse::new(job))
+    }
+
+    ///Grpc route to get the state of a background job.
+    async fn get_job(&self, req: Request<JobId>) -> Result<TonicResponse<Job>, Status> {
+        let id = req.into_inner().id;
+        match self.jobs.get(&id).await {
+            Some(job) => Ok(TonicResponse::new(job)),
+            None => Err(Status::not_found(format!("job {id} not found"))),
+        }
+    }
+
+    ///Grpc route to copy the permissions of an identity to another one.
+    async fn clone_permissions(
+        &self,
+        req: Request<CloneInput>,
+    ) -> Result<TonicResponse<Reply>, Status> {
+        let (metadata, ext, payload) = req.into_parts();
+        let uuid = request_uuid(&ext)?;
+        let config = self.config.load_full();
+        let backend = request_backend(&config, &metadata, &ext, "")?;
+        let client = config.client(backend.as_deref()).map_err(error_status)?;
+        match clone_permissions(client, &config.schemas, uuid, &payload).await {
+            Ok(plan) => {
+                self.events
+                    .publish_patch(uuid, &plan.identity, &plan.patch)
+                    .await;
+                Ok(TonicResponse::new(Reply::default()))
+            }
+            Err(e) => {
+                error!("failed to clone permissions: {e}");
+                Err(error_status(e))
+            }
+        }
+    }
+
+    ///Grpc route streaming the permission changes applied from now on, the changes can be
+    ///filtered by identity or perm_type.
+    async fn watch_permissions(
+        &self,
+        req: Request<WatchInput>,
+    ) -> Result<TonicResponse<Self::WatchPermissionsStream>, Status> {
+        let (_, ext, filter) = req.into_parts();
+        let uuid = request_uuid(&ext)?.to_owned();
+        info!("{uuid}: watching permissions");
+        let stream = watch_stream(uuid, filter, self.events.subscribe(), self.shutdown.clone());
+        Ok(TonicResponse::new(Box::pin(stream)))
+    }
+}
+
+#[cfg(test)]
+mod test_grpc_router {
+    use std::{collections::HashMap, sync::Arc};
+
+    use arc_swap::ArcSwap;
+    use axum::http::HeaderValue;
+    use rs_utils::config::Config;
+    use serde_json::json;
+    use tonic::Code;
+
+    use super::*;
+    use crate::{
+        config::{Shutdown, CONFIG_FALLBACK},
+        limit::ClientIdentity,
+    };
+
+    #[tokio::test]
+    async fn test_modes() {
+        let config: SharedConfig =
+            Arc::new(ArcSwap::from_pointee(IamConfig::new(CONFIG_FALLBACK).await));
+        let iam = MyIam {
+            events: EventPublisher::new(config.clone(), Default::default()),
+            config,
+            jobs: JobRegistry::default(),
+            shutdown: ShutdownCoordinator::default(),
         };
-        let resp = kratos(client, uuid, payload, "replace").await;
-        if let Err(e) = resp {
-            return Err(Status::internal(e.to_string()));
+        for (mode, current) in [
+            (Mode::Admin, "owner"),
+            (Mode::Public, "viewer"),
+            (Mode::Trait, "member"),
+        ] {
+            let mut request = Request::new(Input {
+                id: "modes".to_owned(),
+                perm_type: "project".to_owned(),
+                resource: "222".to_owned(),
+                value: "\"editor\"".to_owned(),
+                mode: mode.into(),
+                ..Default::default()
+            });
+            request
+                .extensions_mut()
+                .insert(RequestId::new(HeaderValue::from_static("test")));
+            let reply = iam.add_permission(request).await.unwrap().into_inner();
+            assert_eq!(reply.id, "modes");
+            assert_eq!(reply.value, "\"editor\"");
+            let permissions: serde_json::Value = serde_json::from_str(&reply.permissions).unwrap();
+            assert_eq!(
+                permissions,
+                json!({"111": current, "222": "editor"}),
+                "{mode:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_request_backend() {
+        let config = IamConfig {
+            backend_callers: HashMap::from([("uid:1000".to_owned(), "eu".to_owned())]),
+            ..Default::default()
+        };
+        let mut metadata = MetadataMap::new();
+        metadata.insert(BACKEND_HEADER, "us".parse().unwrap());
+        let anonymous = Extensions::new();
+        let backend = request_backend(&config, &metadata, &anonymous, "").unwrap();
+        assert_eq!(backend.as_deref(), Some("us"));
+
+        let mut bound = Extensions::new();
+        bound.insert(ClientIdentity(Some("uid:1000".to_owned())));
+        let denied = request_backend(&config, &metadata, &bound, "").unwrap_err();
+        assert_eq!(denied.code(), Code::PermissionDenied);
+        let denied = request_backend(&config, &MetadataMap::new(), &bound, "us").unwrap_err();
+        assert_eq!(denied.code(), Code::PermissionDenied);
+        let backend = request_backend(&config, &MetadataMap::new(), &bound, "").unwrap();
+        assert_eq!(backend.as_deref(), Some("eu"));
+    }
+
+    fn event(id: &str, perm_type: &str) -> PermissionEvent {
+        PermissionEvent {
+            uuid: "test".to_owned(),
+            id: id.to_owned(),
+            mode: "Public".to_owned(),
+            perm_type: perm_type.to_owned(),
+            resource: "1".to_owned(),
+            op: "add".to_owned(),
+            value: serde_json::json!("owner"),
         }
-        Ok(TonicResponse::new(Reply {}))
+    }
+
+    #[test]
+    fn test_watched_change() {
+        let filter = WatchInput {
+            id: "1".to_owned(),
+            perm_type: String::new(),
+        };
+        let change = watched_change(&filter, event("1", "project")).unwrap();
+        assert_eq!(change.mode(), Mode::Public);
+        assert_eq!(change.value, "\"owner\"");
+        assert!(watched_change(&filter, event("2", "project")).is_none());
+        let filter = WatchInput {
+            id: String::new(),
+            perm_type: "group".to_owned(),
+        };
+        assert!(watched_change(&filter, event("1", "project")).is_none());
+        assert!(watched_change(&filter, event("2", "group")).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_watch_lagged() {
+        let (sender, receiver) = broadcast::channel(1);
+        let mut stream = Box::pin(watch_stream(
+            "test".to_owned(),
+            WatchInput::default(),
+            receiver,
+            ShutdownCoordinator::default(),
+        ));
+        sender.send(event("1", "project")).unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().id, "1");
+        sender.send(event("2", "project")).unwrap();
+        sender.send(event("3", "project")).unwrap();
+        let lagged = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(lagged.code(), Code::DataLoss);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_watch_drained() {
+        let (_sender, receiver) = broadcast::channel(1);
+        let coordinator = ShutdownCoordinator::default();
+        let mut stream = Box::pin(watch_stream(
+            "test".to_owned(),
+            WatchInput::default(),
+            receiver,
+            coordinator.clone(),
+        ));
+        let config = Shutdown {
+            pre_stop: 0,
+            timeout: 1,
+        };
+        coordinator.shutdown(config).await;
+        let drained = stream.next().await.unwrap().unwrap_err();
+        assert_eq!(drained.code(), Code::Unavailable);
+        assert!(stream.next().await.is_none());
     }
 }
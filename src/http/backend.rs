@@ -0,0 +1,104 @@
+use axum::{
+    async_trait,
+    extract::{FromRef, FromRequestParts},
+    http::request::Parts,
+};
+use thiserror::Error;
+
+use crate::{
+    config::{IamConfig, SharedConfig},
+    http::error::RouterError,
+    limit::ClientIdentity,
+};
+
+///Header selecting the kratos backend of a request.
+pub const BACKEND_HEADER: &str = "x-iam-backend";
+
+///Kratos backend requested with the backend header and backend the caller is bound to by its
+///client certificate.
+#[derive(Debug, Default)]
+pub struct Backend {
+    requested: Option<String>,
+    bound: Option<String>,
+}
+
+///Error of a caller selecting another backend than the one it is bound to.
+#[derive(Debug, Error)]
+#[error("the caller is bound to the backend {0}")]
+pub struct BackendDenied(pub String);
+
+impl From<BackendDenied> for RouterError {
+    fn from(e: BackendDenied) -> Self {
+        RouterError::BadRequest(e.to_string())
+    }
+}
+
+impl Backend {
+    ///Backend requested by a caller, it is bound to a backend if listed in `backend_callers`.
+    pub fn new(requested: Option<String>, caller: Option<&str>, config: &IamConfig) -> Self {
+        let bound = caller.and_then(|caller| config.backend_callers.get(caller).cloned());
+        Backend { requested, bound }
+    }
+
+    ///Select the backend of the request, the one given in the payload overrides the header. A
+    ///caller bound to a backend can only select it, none selects the default backend.
+    pub fn select(self, payload: &str) -> Result<Option<String>, BackendDenied> {
+        let requested = match payload.is_empty() {
+            true => self.requested,
+            false => Some(payload.to_owned()),
+        };
+        match (requested, self.bound) {
+            (Some(requested), Some(bound)) if requested != bound => Err(BackendDenied(bound)),
+            (requested, bound) => Ok(bound.or(requested)),
+        }
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Backend
+where
+    SharedConfig: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = RouterError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let requested = match parts.headers.get(BACKEND_HEADER) {
+            Some(backend) => Some(backend.to_str()?.to_owned()),
+            None => None,
+        };
+        let caller = match parts.extensions.get::<ClientIdentity>() {
+            Some(ClientIdentity(Some(name))) => Some(name.as_str()),
+            _ => None,
+        };
+        Ok(Backend::new(
+            requested,
+            caller,
+            &SharedConfig::from_ref(state).load(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test_backend {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let backend = Backend {
+            requested: Some("eu".to_owned()),
+            bound: None,
+        };
+        assert_eq!(backend.select("us").unwrap().as_deref(), Some("us"));
+        let backend = Backend {
+            requested: None,
+            bound: Some("eu".to_owned()),
+        };
+        assert_eq!(backend.select("").unwrap().as_deref(), Some("eu"));
+        let backend = Backend {
+            requested: Some("us".to_owned()),
+            bound: Some("eu".to_owned()),
+        };
+        assert!(backend.select("").is_err());
+    }
+}
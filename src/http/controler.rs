@@ -1,14 +1,48 @@
-use anyhow::{Context, Result};
-use serde_json::{json, Value};
-use tracing::{debug, info};
-
-#[allow(unused_imports)]
-use ory_kratos_client::{
-    apis::{configuration::Configuration, identity_api::patch_identity},
-    models::{Identity, JsonPatch},
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use tracing::{debug, error, info};
+use utoipa::ToSchema;
+
+use ory_kratos_client::models::{Identity, JsonPatch};
+
+use crate::{
+    event::{EventPublisher, PermissionEvent},
+    job::JobRegistry,
+    kratos::KratosClient,
+    permission::{CloneInput, CloneStrategy, Input, JobState, Mode, PurgeInput},
+    reconcile::IdentityPlan,
+    schema::{validate, validate_patch, Schemas},
 };
 
-use crate::permission::{Input, Mode};
+///Return the root path of the identity data corresponding to the given mode.
+pub fn mode_root(mode: Mode) -> &'static str {
+    match mode {
+        Mode::Admin => "metadata_admin",
+        Mode::Public => "metadata_public",
+        Mode::Trait => "traits",
+    }
+}
+
+///Escape a key of the identity data to use it as a segment of a json pointer.
+pub fn escape_segment(segment: &str) -> String {
+    segment.replace('~', "~0").replace('/', "~1")
+}
+
+///Get back the key of the identity data from a segment of a json pointer.
+pub fn unescape_segment(segment: &str) -> String {
+    segment.replace("~1", "/").replace("~0", "~")
+}
+
+///Return the root path and the data of the identity corresponding to the given mode.
+pub fn mode_data(identity: &Identity, mode: Mode) -> (&'static str, &Option<Value>) {
+    let data = match mode {
+        Mode::Admin => &identity.metadata_admin,
+        Mode::Public => &identity.metadata_public,
+        Mode::Trait => &identity.traits,
+    };
+    (mode_root(mode), data)
+}
 
 ///This function create an empty map exist at asked path.
 fn patch_empty_meta(root: &str, patch_vec: &mut Vec<JsonPatch>, uuid: &str) -> Result<()> {
@@ -25,69 +59,27 @@ fn patch_empty_meta(root: &str, patch_vec: &mut Vec<JsonPatch>, uuid: &str) -> R
 
 ///This function make sure that an empty map of the type being patch exist if not it add an empty
 ///one.
-async fn verify_type_path(
-    _client: &Configuration,
+fn verify_type_path(
+    identity: &Identity,
     uuid: &str,
     payload: &Input,
 ) -> Result<Option<Vec<JsonPatch>>> {
     let mut patch_vec = Vec::new();
-    #[cfg(not(test))]
-    let identity =
-        ory_kratos_client::apis::identity_api::get_identity(_client, &payload.id, None).await?;
-    #[cfg(test)]
-    let identity = {
-        let mut identity = Identity::new(
-            "test".to_owned(),
-            "test".to_owned(),
-            "test".to_owned(),
-            None,
-        );
-        identity.metadata_admin = Some(serde_json::Value::String("test".to_owned()));
-        identity
-    };
-    let (root, meta) = match payload.mode() {
-        Mode::Admin => {
-            let root = "metadata_admin";
-            let meta = match &identity.metadata_admin {
-                Some(meta) => meta,
-                None => {
-                    patch_empty_meta(root, &mut patch_vec, uuid)?;
-                    &Value::Null
-                }
-            };
-            (root, meta)
-        }
-        Mode::Public => {
-            let root = "metadata_public";
-            let meta = match &identity.metadata_public {
-                Some(meta) => meta,
-                None => {
-                    patch_empty_meta(root, &mut patch_vec, uuid)?;
-                    &Value::Null
-                }
-            };
-            (root, meta)
-        }
-        Mode::Trait => {
-            let root = "traits";
-            let meta = match &identity.traits {
-                Some(meta) => meta,
-                None => {
-                    patch_empty_meta(root, &mut patch_vec, uuid)?;
-                    &Value::Null
-                }
-            };
-            (root, meta)
+    let (root, meta) = mode_data(identity, payload.mode());
+    let meta = match meta {
+        Some(meta) => meta,
+        None => {
+            patch_empty_meta(root, &mut patch_vec, uuid)?;
+            &Value::Null
         }
     };
     debug!("identity: {:#?}", identity);
-    let pointer = meta.pointer(&("/".to_owned() + &payload.perm_type as &str));
-    if pointer.is_none() {
+    if meta.get(&payload.perm_type).is_none() {
         info!(
             "{uuid}: {} do not exit, adding it to metadata",
             payload.perm_type
         );
-        let path = "/".to_owned() + root + "/" + &payload.perm_type as &str;
+        let path = "/".to_owned() + root + "/" + &escape_segment(&payload.perm_type);
         let value = serde_json::from_str::<Value>(&payload.value)?;
         let patch = if value.is_null() || payload.resource == "-" {
             json!({"op" : "add", "path" : path, "value" : [] })
@@ -101,35 +93,47 @@ async fn verify_type_path(
     Ok(None)
 }
 
-///Make request to change the identity coresponding to the user id (uuid)
-///with the given data (payload) and instruction (op).
-pub async fn kratos(_client: &Configuration, uuid: &str, payload: Input, op: &str) -> Result<()> {
+///Build the json patch applying the instruction (op) with the given data (payload).
+///The identity is needed to check the existence of the patched path, for the remove instruction
+///it gives the path of the removed entry, whether it is a key of a map or an element of a list.
+fn build_patch(
+    identity: Option<&Identity>,
+    uuid: &str,
+    payload: &Input,
+    op: &str,
+) -> Result<Vec<JsonPatch>> {
+    if let Some(identity) = identity.filter(|_| op == "remove") {
+        let (root, meta) = mode_data(identity, payload.mode());
+        let path = meta
+            .as_ref()
+            .and_then(|meta| resource_path(meta, root, &payload.perm_type, &payload.resource));
+        let Some(path) = path else {
+            bail!(
+                "{uuid}: {} does not have {}/{}",
+                payload.id,
+                payload.perm_type,
+                payload.resource
+            );
+        };
+        let patch = serde_json::from_value::<JsonPatch>(json!({"op": "remove", "path": path}))
+            .context(format!("{uuid}:"))?;
+        return Ok(vec![patch]);
+    }
     let mut patch_vec = Vec::new();
-    if op != "remove" {
-        if let Some(mut json_patch) = verify_type_path(_client, uuid, &payload).await? {
+    if let Some(identity) = identity {
+        if let Some(mut json_patch) = verify_type_path(identity, uuid, payload)? {
             patch_vec.append(&mut json_patch);
         };
     }
-    info!("{uuid}: Patching identity");
-    let root = match payload.mode() {
-        Mode::Admin => "metadata_admin",
-        Mode::Public => "metadata_public",
-        Mode::Trait => "trait",
-    };
+    let root = mode_root(payload.mode());
     let mut value = serde_json::from_str::<Value>(&payload.value)?;
+    let path = "/".to_owned() + root + "/" + &escape_segment(&payload.perm_type);
     let path = match value {
         Value::Null => {
-            value = Value::String(payload.resource);
-            "/".to_owned() + root + "/" + &payload.perm_type as &str + "/-"
-        }
-        _ => {
-            "/".to_owned()
-                + root
-                + "/"
-                + &payload.perm_type as &str
-                + "/"
-                + &payload.resource as &str
+            value = Value::String(payload.resource.clone());
+            path + "/-"
         }
+        _ => path + "/" + &escape_segment(&payload.resource),
     };
     let raw_patch = json!({
         "op": op,
@@ -141,12 +145,304 @@ pub async fn kratos(_client: &Configuration, uuid: &str, payload: Input, op: &st
     let patch = serde_json::from_value::<JsonPatch>(raw_patch).context(format!("{uuid}:"))?;
     patch_vec.push(patch);
     debug!("vec patch: {:?}", patch_vec);
-    #[cfg(not(test))]
-    patch_identity(_client, &payload.id, Some(patch_vec))
+    Ok(patch_vec)
+}
+
+///State of the modified permission once an instruction is applied.
+#[derive(Serialize, ToSchema, Debug, Default)]
+pub struct PermissionState {
+    ///The id of the modified identity.
+    pub id: String,
+    ///The value at the modified path, null if it was removed.
+    pub value: Value,
+    ///The whole data of the modified perm_type.
+    pub permissions: Value,
+}
+
+impl PermissionState {
+    ///Extract the state of the permission targeted by the payload from the identity.
+    pub fn new(identity: &Identity, payload: &Input) -> Self {
+        let permissions = mode_data(identity, payload.mode())
+            .1
+            .as_ref()
+            .and_then(|meta| meta.get(&payload.perm_type))
+            .cloned()
+            .unwrap_or_default();
+        let value = match &permissions {
+            Value::Array(list) if list.contains(&Value::String(payload.resource.clone())) => {
+                Value::String(payload.resource.clone())
+            }
+            Value::Object(map) => map.get(&payload.resource).cloned().unwrap_or_default(),
+            _ => Value::Null,
+        };
+        PermissionState {
+            id: identity.id.clone(),
+            value,
+            permissions,
+        }
+    }
+}
+
+///Make request to change the identity coresponding to the user id (uuid)
+///with the given data (payload) and instruction (op).
+pub async fn kratos(
+    client: &KratosClient,
+    schemas: &Schemas,
+    uuid: &str,
+    payload: Input,
+    op: &str,
+) -> Result<PermissionState> {
+    if op != "remove" {
+        validate(schemas, &payload).context(format!("{uuid}:"))?;
+    }
+    let identity = client.fetch_identity(&payload.id).await?;
+    let patch_vec = build_patch(Some(&identity), uuid, &payload, op)?;
+    info!("{uuid}: Patching identity");
+    let identity = client
+        .patch_identity(&payload.id, patch_vec)
         .await
         .context(format!("{uuid}:"))?;
     info!("{uuid}: Identity patching sucessfull");
-    Ok(())
+    Ok(PermissionState::new(&identity, &payload))
+}
+
+///Result of an instruction run in dry run mode.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct DryRun {
+    ///The json patch that would be sent to kratos.
+    #[schema(value_type = Vec<Object>)]
+    pub patch: Vec<JsonPatch>,
+    ///The data of the patched mode once the patch is applied.
+    pub preview: Value,
+}
+
+///Response of the add, remove and replace instructions: the state of the permission, or the
+///result of the dry run in dry run mode.
+#[derive(Serialize, ToSchema, Debug)]
+#[serde(untagged)]
+pub enum PolicyResponse {
+    State(PermissionState),
+    DryRun(DryRun),
+}
+
+///Validate the instruction (op) and build its patch without sending it to kratos, the patch is
+///applied to a copy of the identity data to preview the result.
+pub async fn dry_run(
+    client: &KratosClient,
+    schemas: &Schemas,
+    uuid: &str,
+    payload: &Input,
+    op: &str,
+) -> Result<DryRun> {
+    info!("{uuid}: dry run of {op} on identity");
+    if op != "remove" {
+        validate(schemas, payload).context(format!("{uuid}:"))?;
+    }
+    // nothing is patched, the cached identity is enough
+    let identity = client.get_identity(&payload.id).await?;
+    let patch = build_patch(Some(&identity), uuid, payload, op)?;
+    let (root, meta) = mode_data(&identity, payload.mode());
+    let mut doc = Map::new();
+    if let Some(meta) = meta {
+        doc.insert(root.to_owned(), meta.clone());
+    }
+    let mut doc = Value::Object(doc);
+    let operations = serde_json::from_value::<json_patch::Patch>(serde_json::to_value(&patch)?)
+        .context(format!("{uuid}:"))?;
+    json_patch::patch(&mut doc, &operations).context(format!("{uuid}: invalid patch"))?;
+    Ok(DryRun {
+        patch,
+        preview: doc[root].take(),
+    })
+}
+
+///Return the path of the `resource` entry of `perm_type` in the given metadata if it exists.
+///The entry can either be a key of a map or an element of a list.
+fn resource_path(meta: &Value, root: &str, perm_type: &str, resource: &str) -> Option<String> {
+    let path = "/".to_owned() + root + "/" + &escape_segment(perm_type) + "/";
+    match meta.get(perm_type)? {
+        Value::Object(map) if map.contains_key(resource) => Some(path + &escape_segment(resource)),
+        Value::Array(list) => list
+            .iter()
+            .position(|value| value.as_str() == Some(resource))
+            .map(|index| path + &index.to_string()),
+        _ => None,
+    }
+}
+
+///Scan every identity and remove the asked resource from the one that have it.
+async fn purge(
+    client: &KratosClient,
+    jobs: &JobRegistry,
+    events: &EventPublisher,
+    job_id: &str,
+    payload: &PurgeInput,
+) -> Result<()> {
+    let mut page_token = None;
+    loop {
+        let (identities, next) = client.list_identities(page_token.as_deref()).await?;
+        for identity in &identities {
+            let (root, meta) = mode_data(identity, payload.mode());
+            let path = meta
+                .as_ref()
+                .and_then(|meta| resource_path(meta, root, &payload.perm_type, &payload.resource));
+            if let Some(path) = path {
+                if !payload.dry_run {
+                    let patch = json!({"op": "remove", "path": path});
+                    debug!("{job_id}: patch {} with {patch}", identity.id);
+                    let patch =
+                        serde_json::from_value::<JsonPatch>(patch).context(format!("{job_id}:"))?;
+                    client
+                        .patch_identity(&identity.id, vec![patch])
+                        .await
+                        .context(format!("{job_id}:"))?;
+                    let event = PermissionEvent {
+                        uuid: job_id.to_owned(),
+                        id: identity.id.clone(),
+                        mode: payload.mode().as_str_name().to_owned(),
+                        perm_type: payload.perm_type.clone(),
+                        resource: payload.resource.clone(),
+                        op: "remove".to_owned(),
+                        value: Value::Null,
+                    };
+                    events.publish(event).await;
+                }
+                jobs.update(job_id, |job| {
+                    job.matched += 1;
+                    job.identities.push(identity.id.clone());
+                })
+                .await;
+            }
+            jobs.update(job_id, |job| job.scanned += 1).await;
+        }
+        if next.is_none() {
+            return Ok(());
+        }
+        page_token = next;
+    }
+}
+
+///Remove the `perm_type/resource` entry from all the identities, the progress is reported in
+///the job registry under `job_id` and an event is published for each patched identity. In dry
+///run mode the identities are only listed.
+pub async fn purge_resource(
+    client: KratosClient,
+    jobs: JobRegistry,
+    events: EventPublisher,
+    job_id: String,
+    payload: PurgeInput,
+) {
+    info!(
+        "{job_id}: purging {}/{} from identities",
+        payload.perm_type, payload.resource
+    );
+    match purge(&client, &jobs, &events, &job_id, &payload).await {
+        Ok(()) => {
+            info!("{job_id}: purge done");
+            jobs.update(&job_id, |job| job.set_state(JobState::Done))
+                .await
+        }
+        Err(e) => {
+            error!("{job_id}: purge failed: {e:?}");
+            jobs.update(&job_id, |job| {
+                job.set_state(JobState::Failed);
+                job.error = e.to_string();
+            })
+            .await
+        }
+    }
+}
+
+///Build the json patch operations that copy the permissions of `source` into `target`. The
+///metadata are copied by default, the traits hold the identifiers of the identity so they are
+///only copied when the trait mode is asked with explicit perm_types.
+pub fn clone_patch(source: &Identity, target: &Identity, payload: &CloneInput) -> Vec<Value> {
+    let mut modes: Vec<Mode> = payload.modes().collect();
+    if modes.is_empty() {
+        modes = vec![Mode::Admin, Mode::Public];
+    }
+    if payload.perm_types.is_empty() {
+        modes.retain(|mode| *mode != Mode::Trait);
+    }
+    let mut patches = Vec::new();
+    for mode in modes {
+        let (root, source_meta) = mode_data(source, mode);
+        let Some(Value::Object(source_meta)) = source_meta else {
+            continue;
+        };
+        let target_meta = match mode_data(target, mode).1 {
+            Some(Value::Object(meta)) => Some(meta),
+            _ => None,
+        };
+        if target_meta.is_none() {
+            patches.push(json!({"op": "add", "path": "/".to_owned() + root, "value": {}}));
+        }
+        for (perm_type, value) in source_meta {
+            if !payload.perm_types.is_empty() && !payload.perm_types.contains(perm_type) {
+                continue;
+            }
+            let path = "/".to_owned() + root + "/" + &escape_segment(perm_type);
+            let current = target_meta.and_then(|meta| meta.get(perm_type));
+            match (payload.strategy(), current, value) {
+                (CloneStrategy::Merge, Some(Value::Object(current)), Value::Object(value)) => {
+                    // the resources of the target keep their value
+                    for (resource, value) in value {
+                        if !current.contains_key(resource) {
+                            let path = path.clone() + "/" + &escape_segment(resource);
+                            patches.push(json!({"op": "add", "path": path, "value": value}));
+                        }
+                    }
+                }
+                (CloneStrategy::Merge, Some(Value::Array(current)), Value::Array(value)) => {
+                    for resource in value.iter().filter(|resource| !current.contains(resource)) {
+                        let path = path.clone() + "/-";
+                        patches.push(json!({"op": "add", "path": path, "value": resource}));
+                    }
+                }
+                // a perm_type of another json type in the target is kept
+                (CloneStrategy::Merge, Some(_), _) => (),
+                _ => patches.push(json!({"op": "add", "path": path, "value": value})),
+            }
+        }
+    }
+    patches
+}
+
+///Copy the permissions of an identity to another one in a single patch, the existing
+///permissions of the target are either merged with or overwritten by the source ones. The copied
+///permissions are checked against the perm_type schemas and the plan applied to the target is
+///returned.
+pub async fn clone_permissions(
+    client: &KratosClient,
+    schemas: &Schemas,
+    uuid: &str,
+    payload: &CloneInput,
+) -> Result<IdentityPlan> {
+    info!(
+        "{uuid}: cloning permissions of {} into {}",
+        payload.source, payload.target
+    );
+    // only the target is patched
+    let source = client.get_identity(&payload.source).await?;
+    let target = client.fetch_identity(&payload.target).await?;
+    let patch = clone_patch(&source, &target, payload);
+    let mut plan = IdentityPlan::new(target, patch);
+    if plan.patch.is_empty() {
+        info!("{uuid}: nothing to clone");
+        return Ok(plan);
+    }
+    let patch = &plan.patch;
+    validate_patch(schemas, patch).context(format!("{uuid}:"))?;
+    let json = serde_json::from_value::<Vec<JsonPatch>>(Value::Array(patch.clone()))
+        .context(format!("{uuid}:"))?;
+    debug!("{uuid}: clone patch: {:?}", json);
+    client
+        .patch_identity(&payload.target, json)
+        .await
+        .context(format!("{uuid}:"))?;
+    info!("{uuid}: Identity patching sucessfull");
+    plan.applied = true;
+    Ok(plan)
 }
 
 #[cfg(test)]
@@ -155,7 +451,7 @@ mod test_controler {
 
     #[tokio::test]
     async fn test_kratos_controler() {
-        let client = Configuration::default();
+        let client = KratosClient::default();
         let uuid = "test";
         let payload = Input {
             id: "1".to_owned(),
@@ -163,7 +459,232 @@ mod test_controler {
             resource: "resource".to_owned(),
             value: "\"testting\"".to_owned(),
             mode: 0,
+            dry_run: false,
+            backend: String::new(),
+        };
+        let state = kratos(&client, &Schemas::default(), uuid, payload, "add")
+            .await
+            .unwrap();
+        assert_eq!(state.id, "1");
+        assert_eq!(state.value, json!("testting"));
+        assert_eq!(state.permissions, json!({"resource": "testting"}));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let client = KratosClient::default();
+        let payload = Input {
+            id: "target".to_owned(),
+            perm_type: "organization".to_owned(),
+            resource: "1".to_owned(),
+            value: "\"admin\"".to_owned(),
+            mode: 0,
+            dry_run: true,
+            backend: String::new(),
+        };
+        let result = dry_run(&client, &Schemas::default(), "test", &payload, "add")
+            .await
+            .unwrap();
+        assert_eq!(result.patch.len(), 2);
+        assert_eq!(
+            result.preview,
+            json!({
+                "project": {"111": "owner"},
+                "group": ["2"],
+                "organization": {"1": "admin"}
+            })
+        );
+    }
+
+    #[test]
+    fn test_trait_patch() {
+        let payload = Input {
+            id: "1".to_owned(),
+            perm_type: "group".to_owned(),
+            resource: "1".to_owned(),
+            value: "null".to_owned(),
+            mode: Mode::Trait.into(),
+            ..Default::default()
+        };
+        let patch = build_patch(None, "test", &payload, "add").unwrap();
+        let patch = serde_json::to_value(&patch).unwrap();
+        assert_eq!(patch[0]["path"], "/traits/group/-");
+        let identity = Identity {
+            traits: Some(json!({"group": []})),
+            ..Default::default()
+        };
+        let patch = build_patch(Some(&identity), "test", &payload, "add").unwrap();
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap()[0]["path"],
+            "/traits/group/-"
+        );
+    }
+
+    #[test]
+    fn test_escape_segment() {
+        assert_eq!(escape_segment("a/b~c"), "a~1b~0c");
+        assert_eq!(unescape_segment(&escape_segment("a/b~c")), "a/b~c");
+        let identity = Identity {
+            metadata_admin: Some(json!({"repo": {"a/b": "owner"}})),
+            ..Default::default()
+        };
+        let payload = Input {
+            perm_type: "repo".to_owned(),
+            resource: "a/b".to_owned(),
+            value: "\"viewer\"".to_owned(),
+            ..Default::default()
+        };
+        let patch = build_patch(Some(&identity), "test", &payload, "replace").unwrap();
+        let mut doc = serde_json::to_value(&identity).unwrap();
+        let patch = serde_json::to_value(&patch).unwrap();
+        let patch = serde_json::from_value::<json_patch::Patch>(patch).unwrap();
+        json_patch::patch(&mut doc, &patch).unwrap();
+        assert_eq!(doc["metadata_admin"], json!({"repo": {"a/b": "viewer"}}));
+        let patch = build_patch(Some(&identity), "test", &payload, "remove").unwrap();
+        assert_eq!(
+            serde_json::to_value(&patch).unwrap()[0]["path"],
+            "/metadata_admin/repo/a~1b"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_remove() {
+        let client = KratosClient::default();
+        let mut payload = Input {
+            id: "owner".to_owned(),
+            perm_type: "group".to_owned(),
+            resource: "222".to_owned(),
+            value: "null".to_owned(),
+            ..Default::default()
+        };
+        let state = kratos(
+            &client,
+            &Schemas::default(),
+            "test",
+            payload.clone(),
+            "remove",
+        )
+        .await
+        .unwrap();
+        assert_eq!(state.value, Value::Null);
+        assert_eq!(state.permissions, json!([]));
+        payload.resource = "333".to_owned();
+        assert!(
+            kratos(&client, &Schemas::default(), "test", payload, "remove")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_purge_resource() {
+        let client = KratosClient::default();
+        let jobs = JobRegistry::default();
+        let job = jobs.create(false).await;
+        let payload = PurgeInput {
+            perm_type: "group".to_owned(),
+            resource: "222".to_owned(),
+            mode: 0,
+            dry_run: false,
         };
-        kratos(&client, uuid, payload, "add").await.unwrap();
+        let events = EventPublisher::new(Default::default(), Default::default());
+        let mut watcher = events.subscribe();
+        purge_resource(client, jobs.clone(), events, job.id.clone(), payload).await;
+        let job = jobs.get(&job.id).await.unwrap();
+        assert_eq!(job.state(), JobState::Done);
+        assert_eq!(job.scanned, 2);
+        assert_eq!(job.identities, vec!["owner".to_owned()]);
+        let event = watcher.try_recv().unwrap();
+        assert_eq!(
+            (&*event.id, &*event.op, &*event.resource),
+            ("owner", "remove", "222")
+        );
+    }
+
+    #[test]
+    fn test_clone_conflicts() {
+        let source = Identity {
+            metadata_admin: Some(json!({
+                "project": {"111": "owner", "222": "owner"},
+                "group": ["1"]
+            })),
+            ..Default::default()
+        };
+        let target = Identity {
+            metadata_admin: Some(json!({
+                "project": {"111": "viewer"},
+                "group": {"1": "member"}
+            })),
+            ..Default::default()
+        };
+        let mut payload = CloneInput {
+            strategy: CloneStrategy::Merge.into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            clone_patch(&source, &target, &payload),
+            vec![json!({"op": "add", "path": "/metadata_admin/project/222", "value": "owner"})]
+        );
+        payload.strategy = CloneStrategy::Overwrite.into();
+        assert_eq!(clone_patch(&source, &target, &payload).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_clone_traits() {
+        let client = KratosClient::default();
+        let source = client.fetch_identity("modes").await.unwrap();
+        let target = client.fetch_identity("target").await.unwrap();
+        let mut payload = CloneInput {
+            strategy: CloneStrategy::Overwrite.into(),
+            ..Default::default()
+        };
+        let patch = clone_patch(&source, &target, &payload);
+        assert!(!patch.is_empty());
+        assert!(patch
+            .iter()
+            .all(|op| !op["path"].as_str().unwrap().starts_with("/traits")));
+        payload.modes = vec![Mode::Trait.into()];
+        assert!(clone_patch(&source, &target, &payload).is_empty());
+        payload.perm_types = vec!["project".to_owned()];
+        assert_eq!(
+            clone_patch(&source, &target, &payload),
+            vec![
+                json!({"op": "add", "path": "/traits", "value": {}}),
+                json!({"op": "add", "path": "/traits/project", "value": {"111": "member"}}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_clone_patch() {
+        let client = KratosClient::default();
+        let source = client.fetch_identity("source").await.unwrap();
+        let target = client.fetch_identity("target").await.unwrap();
+        let mut payload = CloneInput {
+            source: "source".to_owned(),
+            target: "target".to_owned(),
+            modes: vec![Mode::Admin.into()],
+            perm_types: Vec::new(),
+            strategy: CloneStrategy::Merge.into(),
+        };
+        let patch = clone_patch(&source, &target, &payload);
+        assert_eq!(
+            patch,
+            vec![
+                json!({"op": "add", "path": "/metadata_admin/group/-", "value": "1"}),
+                json!({"op": "add", "path": "/metadata_admin/project/222", "value": "owner"}),
+                json!({"op": "add", "path": "/metadata_admin/project/333", "value": "viewer"}),
+            ]
+        );
+        payload.strategy = CloneStrategy::Overwrite.into();
+        payload.perm_types = vec!["group".to_owned()];
+        let patch = clone_patch(&source, &target, &payload);
+        assert_eq!(
+            patch,
+            vec![json!({"op": "add", "path": "/metadata_admin/group", "value": ["1"]})]
+        );
+        clone_permissions(&client, &Schemas::default(), "test", &payload)
+            .await
+            .unwrap();
     }
 }
@@ -1,10 +1,17 @@
+use std::collections::BTreeMap;
+
 use axum::{
     http::{header::ToStrError, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
-use ory_kratos_client::apis::{metadata_api::IsReadyError, Error};
 use thiserror::Error;
-use tracing::error;
+use tracing::{error, info};
+
+use crate::{
+    kratos::{IdentityNotFound, KratosBusy, KratosUnavailable, UnknownBackend},
+    schema::SchemaError,
+};
 
 ///handler for error in the http service
 ///it convert the recevied error in a response
@@ -15,12 +22,50 @@ pub enum RouterError {
     #[error("failled to convert to string")]
     StrConvert(#[from] ToStrError),
     #[error("an error ocured when contacting kratos")]
-    Kratos(#[from] Error<IsReadyError>),
+    Kratos(anyhow::Error),
+    #[error("resource not found")]
+    NotFound(String),
+    #[error("invalid request")]
+    BadRequest(String),
+    #[error("rate limit exceeded")]
+    TooManyRequests(String),
+    #[error("the service is shutting down")]
+    ShuttingDown,
+    #[error("a kratos backend is not ready")]
+    NotReady(BTreeMap<String, String>),
 }
 
 impl IntoResponse for RouterError {
     fn into_response(self) -> Response {
         match self {
+            RouterError::Internal(e) if e.downcast_ref::<SchemaError>().is_some() => {
+                error!("{:?}", e);
+                (StatusCode::BAD_REQUEST, format!("BAD_REQUEST: {e:#}")).into_response()
+            }
+            RouterError::Internal(e) if e.downcast_ref::<UnknownBackend>().is_some() => {
+                error!("{:?}", e);
+                (StatusCode::BAD_REQUEST, format!("BAD_REQUEST: {e:#}")).into_response()
+            }
+            RouterError::Internal(e) if e.downcast_ref::<IdentityNotFound>().is_some() => {
+                error!("{:?}", e);
+                (StatusCode::NOT_FOUND, format!("NOT_FOUND: {e:#}")).into_response()
+            }
+            RouterError::Internal(e) if e.downcast_ref::<KratosUnavailable>().is_some() => {
+                error!("{:?}", e);
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    format!("SERVICE_UNAVAILABLE: {e:#}"),
+                )
+                    .into_response()
+            }
+            RouterError::Internal(e) if e.downcast_ref::<KratosBusy>().is_some() => {
+                error!("{:?}", e);
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    format!("TOO_MANY_REQUESTS: {e:#}"),
+                )
+                    .into_response()
+            }
             RouterError::Internal(e) => {
                 error!("{:?}", e);
                 (
@@ -45,6 +90,36 @@ impl IntoResponse for RouterError {
                 )
             }
             .into_response(),
+            RouterError::NotFound(e) => {
+                error!("{e}");
+                (StatusCode::NOT_FOUND, format!("NOT_FOUND: {e}"))
+            }
+            .into_response(),
+            RouterError::BadRequest(e) => {
+                error!("{e}");
+                (StatusCode::BAD_REQUEST, format!("BAD_REQUEST: {e}"))
+            }
+            .into_response(),
+            RouterError::TooManyRequests(caller) => {
+                error!("rate limit exceeded for {caller}");
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    format!("TOO_MANY_REQUESTS: rate limit exceeded for {caller}"),
+                )
+            }
+            .into_response(),
+            RouterError::NotReady(backends) => {
+                error!("Kratos is not ready: {backends:?}");
+                (StatusCode::SERVICE_UNAVAILABLE, Json(backends)).into_response()
+            }
+            RouterError::ShuttingDown => {
+                info!("not ready: the service is shutting down");
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "SERVICE_UNAVAILABLE: the service is shutting down",
+                )
+            }
+            .into_response(),
         }
     }
 }
@@ -1,3 +1,5 @@
+pub mod backend;
 pub mod controler;
 pub mod error;
+pub mod openapi;
 pub mod router;
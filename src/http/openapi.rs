@@ -0,0 +1,138 @@
+use axum::Json;
+use utoipa::{IntoParams, IntoResponses, OpenApi};
+
+use crate::{
+    http::{
+        controler::{DryRun, PermissionState, PolicyResponse},
+        router::{self, WebhookPayload},
+    },
+    permission::{CloneInput, Input, Job, PurgeInput},
+    reconcile::{IdentityPlan, Plan},
+    schema::PermSchema,
+};
+
+///Headers of the routes acting on kratos, only used to document them.
+#[derive(IntoParams)]
+#[into_params(parameter_in = Header)]
+#[allow(dead_code)]
+pub struct KratosHeaders {
+    ///Uuid of the request in the logs, generated when missing.
+    #[param(rename = "x-request-id")]
+    request_id: Option<String>,
+    ///Name of the kratos backend, the default one when missing. The `backend` field of the
+    ///payload overrides it.
+    #[param(rename = "x-iam-backend")]
+    backend: Option<String>,
+}
+
+///Error responses of the routes acting on kratos, the body is the error message.
+#[derive(IntoResponses)]
+#[allow(dead_code)]
+pub enum ErrorResponses {
+    ///Invalid payload, value not matching the perm_type schema or unknown backend.
+    #[response(status = 400)]
+    BadRequest(String),
+    ///Unknown job or cache webhook disabled.
+    #[response(status = 404)]
+    NotFound(String),
+    ///Rate limit of the caller exceeded or too many concurrent kratos requests.
+    #[response(status = 429)]
+    TooManyRequests(String),
+    ///Kratos call failed.
+    #[response(status = 500)]
+    Internal(String),
+    ///Kratos unavailable.
+    #[response(status = 503)]
+    Unavailable(String),
+}
+
+///OpenAPI description of the http api, the payloads are the messages of the proto.
+#[derive(OpenApi)]
+#[openapi(
+    info(description = "Edit the permissions stored in the kratos identities."),
+    paths(
+        router::add,
+        router::remove,
+        router::replace,
+        router::clone,
+        router::purge,
+        router::schema,
+        router::reconcile,
+        router::export,
+        router::import,
+        router::invalidate,
+        router::get_job,
+        router::alive,
+        router::ready,
+        router::running_config,
+    ),
+    components(schemas(
+        Input,
+        PurgeInput,
+        CloneInput,
+        Job,
+        PermissionState,
+        DryRun,
+        PolicyResponse,
+        Plan,
+        IdentityPlan,
+        PermSchema,
+        WebhookPayload,
+    ))
+)]
+pub struct ApiDoc;
+
+///http route returning the OpenAPI description of the http api
+pub async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+#[cfg(test)]
+mod test_openapi {
+    use serde::Serialize;
+    use serde_json::Value;
+
+    use super::*;
+
+    ///Check that the schema of a proto message has the fields of its json representation.
+    fn assert_fields(doc: &Value, name: &str, message: impl Serialize) {
+        let message = serde_json::to_value(message).unwrap();
+        let mut fields: Vec<_> = message.as_object().unwrap().keys().collect();
+        let properties = doc["components"]["schemas"][name]["properties"]
+            .as_object()
+            .unwrap();
+        let mut documented: Vec<_> = properties.keys().collect();
+        fields.sort();
+        documented.sort();
+        assert_eq!(fields, documented, "{name}");
+    }
+
+    #[test]
+    fn test_openapi() {
+        let doc = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let paths = [
+            "/api/iam/policy",
+            "/api/iam/clone",
+            "/api/iam/purge",
+            "/api/iam/reconcile",
+            "/api/iam/export",
+            "/api/iam/import",
+            "/api/iam/schema",
+            "/api/iam/job/{id}",
+            "/api/iam/cache/invalidate",
+            "/api/iam/alive",
+            "/api/iam/ready",
+            "/api/iam/config",
+        ];
+        for path in paths {
+            assert!(doc["paths"][path].is_object(), "{path}");
+        }
+        assert!(doc["paths"]["/api/iam/policy"]["put"]["responses"]["400"].is_object());
+        let policy = &doc["components"]["schemas"]["PolicyResponse"];
+        assert_eq!(policy["oneOf"].as_array().unwrap().len(), 2);
+        assert_fields(&doc, "Input", Input::default());
+        assert_fields(&doc, "PurgeInput", PurgeInput::default());
+        assert_fields(&doc, "CloneInput", CloneInput::default());
+        assert_fields(&doc, "Job", Job::default());
+    }
+}
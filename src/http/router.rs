@@ -1,101 +1,526 @@
-use std::sync::Arc;
+use std::collections::BTreeMap;
 
 use anyhow::anyhow;
 use axum::{
-    extract::{Json, State},
-    response::Result,
+    body::Body,
+    extract::{Json, Path, Query, State},
+    http::{header::CONTENT_TYPE, HeaderMap},
+    response::{IntoResponse, Response, Result},
     Extension,
 };
-use ory_kratos_client::apis::metadata_api::is_ready;
-use tokio::sync::RwLock;
+use futures::future::join_all;
+use serde::Deserialize;
+use serde_json::{json, Value};
 use tower_http::request_id::RequestId;
 use tracing::info;
+use utoipa::{IntoParams, ToSchema};
 
 use crate::{
-    config::IamConfig,
-    http::{controler::kratos, error::RouterError},
-    permission::Input,
+    backup::{export as export_permissions, import as import_permissions},
+    config::{ReloadStatus, SharedConfig},
+    event::{EventPublisher, PermissionEvent},
+    http::{
+        backend::Backend,
+        controler::{clone_permissions, dry_run, kratos, purge_resource, PolicyResponse},
+        error::RouterError,
+        openapi::{ErrorResponses, KratosHeaders},
+    },
+    job::JobRegistry,
+    permission::{CloneInput, CloneStrategy, Input, Job, PurgeInput},
+    reconcile::{reconcile as reconcile_permissions, Desired, DocumentFormat, Plan},
+    schema::{PermSchema, Schemas},
+    shutdown::ShutdownCoordinator,
 };
 
 ///http route to add an identity field
+#[utoipa::path(
+    post,
+    path = "/api/iam/policy",
+    tag = "policy",
+    params(KratosHeaders),
+    request_body = Input,
+    responses(
+        (
+            status = 200,
+            description = "State of the permission, a DryRun in dry run mode",
+            body = PolicyResponse
+        ),
+        ErrorResponses
+    )
+)]
 pub async fn add(
-    State(config): State<Arc<RwLock<IamConfig>>>,
+    State(config): State<SharedConfig>,
+    State(events): State<EventPublisher>,
     request_id: Extension<RequestId>,
+    backend: Backend,
     Json(payload): Json<Input>,
-) -> Result<&'static str, RouterError> {
+) -> Result<Json<PolicyResponse>, RouterError> {
     let uuid = request_id.header_value().to_str()?;
 
     info!("{uuid}: adding data to identity");
-    let config = config.read().await;
-    let client = match &config.kratos.client {
-        Some(client) => client,
-        None => Err(anyhow!("{uuid}: Kratos client not initialized"))?,
-    };
-    kratos(client, uuid, payload, "add").await?;
+    let config = config.load_full();
+    let client = config.client(backend.select(&payload.backend)?.as_deref())?;
+    if payload.dry_run {
+        let result = dry_run(client, &config.schemas, uuid, &payload, "add").await?;
+        return Ok(Json(PolicyResponse::DryRun(result)));
+    }
+    let event = PermissionEvent::new(uuid, &payload, "add");
+    let state = kratos(client, &config.schemas, uuid, payload, "add").await?;
+    events.publish(event.with_value(state.value.clone())).await;
     info!("{uuid}: done");
-    Ok("200")
+    Ok(Json(PolicyResponse::State(state)))
 }
 
 ///http route to remove an identity field
+#[utoipa::path(
+    delete,
+    path = "/api/iam/policy",
+    tag = "policy",
+    params(KratosHeaders),
+    request_body = Input,
+    responses(
+        (
+            status = 200,
+            description = "State of the permission, a DryRun in dry run mode",
+            body = PolicyResponse
+        ),
+        ErrorResponses
+    )
+)]
 pub async fn remove(
-    State(config): State<Arc<RwLock<IamConfig>>>,
+    State(config): State<SharedConfig>,
+    State(events): State<EventPublisher>,
     request_id: Extension<RequestId>,
+    backend: Backend,
     Json(payload): Json<Input>,
-) -> Result<&'static str, RouterError> {
+) -> Result<Json<PolicyResponse>, RouterError> {
     let uuid = request_id.header_value().to_str()?;
     info!("{uuid}: removing data to identity");
-    let config = config.read().await;
-    let client = match &config.kratos.client {
-        Some(client) => client,
-        None => Err(anyhow!("{uuid}: Kratos client not initialized"))?,
-    };
-    kratos(client, uuid, payload, "remove").await?;
+    let config = config.load_full();
+    let client = config.client(backend.select(&payload.backend)?.as_deref())?;
+    if payload.dry_run {
+        let result = dry_run(client, &config.schemas, uuid, &payload, "remove").await?;
+        return Ok(Json(PolicyResponse::DryRun(result)));
+    }
+    let event = PermissionEvent::new(uuid, &payload, "remove");
+    let state = kratos(client, &config.schemas, uuid, payload, "remove").await?;
+    events.publish(event.with_value(state.value.clone())).await;
     info!("{uuid}: done");
-    Ok("200")
+    Ok(Json(PolicyResponse::State(state)))
 }
 
 ///http route to replace an identity field
+#[utoipa::path(
+    put,
+    path = "/api/iam/policy",
+    tag = "policy",
+    params(KratosHeaders),
+    request_body = Input,
+    responses(
+        (
+            status = 200,
+            description = "State of the permission, a DryRun in dry run mode",
+            body = PolicyResponse
+        ),
+        ErrorResponses
+    )
+)]
 pub async fn replace(
-    State(config): State<Arc<RwLock<IamConfig>>>,
+    State(config): State<SharedConfig>,
+    State(events): State<EventPublisher>,
     request_id: Extension<RequestId>,
+    backend: Backend,
     Json(payload): Json<Input>,
-) -> Result<&'static str, RouterError> {
+) -> Result<Json<PolicyResponse>, RouterError> {
     let uuid = request_id.header_value().to_str()?;
     info!("{uuid}: replacing data in identity");
-    let config = config.read().await;
-    let client = match &config.kratos.client {
-        Some(client) => client,
-        None => Err(anyhow!("{uuid}: Kratos client not initialized"))?,
+    let config = config.load_full();
+    let client = config.client(backend.select(&payload.backend)?.as_deref())?;
+    if payload.dry_run {
+        let result = dry_run(client, &config.schemas, uuid, &payload, "replace").await?;
+        return Ok(Json(PolicyResponse::DryRun(result)));
+    }
+    let event = PermissionEvent::new(uuid, &payload, "replace");
+    let state = kratos(client, &config.schemas, uuid, payload, "replace").await?;
+    events.publish(event.with_value(state.value.clone())).await;
+    info!("{uuid}: done");
+    Ok(Json(PolicyResponse::State(state)))
+}
+
+///http route to copy the permissions of an identity to another one
+#[utoipa::path(
+    post,
+    path = "/api/iam/clone",
+    tag = "policy",
+    params(KratosHeaders),
+    request_body = CloneInput,
+    responses((status = 200, description = "Permissions cloned", body = String), ErrorResponses)
+)]
+pub async fn clone(
+    State(config): State<SharedConfig>,
+    State(events): State<EventPublisher>,
+    request_id: Extension<RequestId>,
+    backend: Backend,
+    Json(payload): Json<CloneInput>,
+) -> Result<&'static str, RouterError> {
+    let uuid = request_id.header_value().to_str()?;
+    let config = config.load_full();
+    let client = config.client(backend.select("")?.as_deref())?;
+    let plan = clone_permissions(client, &config.schemas, uuid, &payload).await?;
+    events
+        .publish_patch(uuid, &plan.identity, &plan.patch)
+        .await;
+    info!("{uuid}: done");
+    Ok("200")
+}
+
+///http route to remove a resource from all the identities, the purge is run as a background job
+#[utoipa::path(
+    post,
+    path = "/api/iam/purge",
+    tag = "jobs",
+    params(KratosHeaders),
+    request_body = PurgeInput,
+    responses((status = 200, description = "Purge job launched", body = Job), ErrorResponses)
+)]
+pub async fn purge(
+    State(config): State<SharedConfig>,
+    State(jobs): State<JobRegistry>,
+    State(events): State<EventPublisher>,
+    State(shutdown): State<ShutdownCoordinator>,
+    request_id: Extension<RequestId>,
+    backend: Backend,
+    Json(payload): Json<PurgeInput>,
+) -> Result<Json<Job>, RouterError> {
+    let uuid = request_id.header_value().to_str()?;
+    info!("{uuid}: launching resource purge");
+    let client = config
+        .load()
+        .client(backend.select("")?.as_deref())?
+        .clone();
+    let job = jobs.create(payload.dry_run).await;
+    info!("{uuid}: purge job {}", job.id);
+    let task = shutdown.tasks.track();
+    let purge = purge_resource(client, jobs, events, job.id.clone(), payload);
+    tokio::spawn(async move {
+        purge.await;
+        drop(task);
+    });
+    Ok(Json(job))
+}
+
+///http route listing the schemas of the perm_types
+#[utoipa::path(
+    get,
+    path = "/api/iam/schema",
+    tag = "policy",
+    responses(
+        (status = 200, description = "Schemas by perm_type", body = BTreeMap<String, PermSchema>)
+    )
+)]
+pub async fn schema(State(config): State<SharedConfig>) -> Result<Json<Schemas>, RouterError> {
+    Ok(Json(config.load().schemas.clone()))
+}
+
+///Query parameters of the reconcile route.
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub struct ReconcileQuery {
+    ///Apply the plan instead of only returning it.
+    #[serde(default)]
+    apply: bool,
+}
+
+///http route to reconcile the identities with a declarative permission document, the document
+///format is given by the content type. Without the `apply` query parameter only the plan is
+///returned.
+#[utoipa::path(
+    post,
+    path = "/api/iam/reconcile",
+    tag = "policy",
+    params(KratosHeaders, ReconcileQuery),
+    request_body(
+        content = Object,
+        description = "Desired permissions by identity and mode, in json, yaml or toml",
+        content_type = "application/json"
+    ),
+    responses((status = 200, description = "Reconciliation plan", body = Plan), ErrorResponses)
+)]
+pub async fn reconcile(
+    State(config): State<SharedConfig>,
+    State(events): State<EventPublisher>,
+    request_id: Extension<RequestId>,
+    backend: Backend,
+    Query(query): Query<ReconcileQuery>,
+    headers: HeaderMap,
+    body: String,
+) -> Result<Json<Plan>, RouterError> {
+    let uuid = request_id.header_value().to_str()?;
+    info!("{uuid}: reconciling permissions, apply: {}", query.apply);
+    let content_type = match headers.get(CONTENT_TYPE) {
+        Some(content_type) => content_type.to_str()?,
+        None => "application/json",
     };
-    kratos(client, uuid, payload, "replace").await?;
+    let desired = Desired::parse(&body, DocumentFormat::from_content_type(content_type))
+        .map_err(|e| RouterError::BadRequest(format!("{uuid}: {e:#}")))?;
+    let config = config.load_full();
+    let client = config.client(backend.select("")?.as_deref())?;
+    let plan = reconcile_permissions(client, &config.schemas, uuid, &desired, query.apply).await?;
+    events.publish_plan(uuid, &plan).await;
+    info!("{uuid}: done");
+    Ok(Json(plan))
+}
+
+///Query parameters of the export route.
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub struct ExportQuery {
+    ///Comma separated list of the permission related traits to export.
+    #[serde(default)]
+    traits: String,
+}
+
+///http route streaming the permission data of every identity as ndjson
+#[utoipa::path(
+    get,
+    path = "/api/iam/export",
+    tag = "backup",
+    params(KratosHeaders, ExportQuery),
+    responses(
+        (
+            status = 200,
+            description = "Permissions of each identity",
+            body = String,
+            content_type = "application/x-ndjson"
+        ),
+        ErrorResponses
+    )
+)]
+pub async fn export(
+    State(config): State<SharedConfig>,
+    request_id: Extension<RequestId>,
+    backend: Backend,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response, RouterError> {
+    let uuid = request_id.header_value().to_str()?;
+    let client = config
+        .load()
+        .client(backend.select("")?.as_deref())?
+        .clone();
+    let traits = query
+        .traits
+        .split(',')
+        .filter(|key| !key.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let stream = export_permissions(client, uuid.to_owned(), traits);
+    Ok((
+        [(CONTENT_TYPE, "application/x-ndjson")],
+        Body::from_stream(stream),
+    )
+        .into_response())
+}
 
+///Query parameters of the import route.
+#[derive(Deserialize, IntoParams, Debug)]
+#[into_params(parameter_in = Query)]
+pub struct ImportQuery {
+    ///Replace the exported perm_types instead of merging them with the existing ones.
+    #[serde(default)]
+    overwrite: bool,
+    ///Only return the patches without modifying the identities.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+///http route importing the permission data of an ndjson export, the exported permissions are
+///merged with the existing ones unless `overwrite` is set
+#[utoipa::path(
+    post,
+    path = "/api/iam/import",
+    tag = "backup",
+    params(KratosHeaders, ImportQuery),
+    request_body(
+        content = String,
+        description = "Ndjson export",
+        content_type = "application/x-ndjson"
+    ),
+    responses((status = 200, description = "Import plan", body = Plan), ErrorResponses)
+)]
+pub async fn import(
+    State(config): State<SharedConfig>,
+    State(events): State<EventPublisher>,
+    request_id: Extension<RequestId>,
+    backend: Backend,
+    Query(query): Query<ImportQuery>,
+    body: String,
+) -> Result<Json<Plan>, RouterError> {
+    let uuid = request_id.header_value().to_str()?;
+    info!("{uuid}: importing permissions, dry run: {}", query.dry_run);
+    let strategy = match query.overwrite {
+        true => CloneStrategy::Overwrite,
+        false => CloneStrategy::Merge,
+    };
+    let config = config.load_full();
+    let client = config.client(backend.select("")?.as_deref())?;
+    let plan = import_permissions(
+        client,
+        &config.schemas,
+        uuid,
+        &body,
+        strategy,
+        !query.dry_run,
+    )
+    .await?;
+    events.publish_plan(uuid, &plan).await;
     info!("{uuid}: done");
+    Ok(Json(plan))
+}
+
+///Payload sent by the kratos webhooks, only the identity id is used.
+#[derive(Deserialize, ToSchema, Debug)]
+pub struct WebhookPayload {
+    #[schema(inline)]
+    identity: WebhookIdentity,
+}
+
+#[derive(Deserialize, ToSchema, Debug)]
+struct WebhookIdentity {
+    id: String,
+}
+
+///http route called by the kratos webhooks to remove the modified identity from the cache
+#[utoipa::path(
+    post,
+    path = "/api/iam/cache/invalidate",
+    tag = "cache",
+    params(KratosHeaders),
+    request_body = WebhookPayload,
+    responses(
+        (status = 200, description = "Identity removed from the cache", body = String),
+        ErrorResponses
+    )
+)]
+pub async fn invalidate(
+    State(config): State<SharedConfig>,
+    backend: Backend,
+    Json(payload): Json<WebhookPayload>,
+) -> Result<&'static str, RouterError> {
+    let config = config.load();
+    if !config.cache.webhook {
+        return Err(RouterError::NotFound("cache webhook disabled".to_owned()));
+    }
+    let client = config.client(backend.select("")?.as_deref())?;
+    client.invalidate(&payload.identity.id);
     Ok("200")
 }
 
+///http route to get the state of a background job
+#[utoipa::path(
+    get,
+    path = "/api/iam/job/{id}",
+    tag = "jobs",
+    params(("id" = String, Path, description = "Id of the job")),
+    responses((status = 200, description = "State of the job", body = Job), ErrorResponses)
+)]
+pub async fn get_job(
+    State(jobs): State<JobRegistry>,
+    Path(id): Path<String>,
+) -> Result<Json<Job>, RouterError> {
+    match jobs.get(&id).await {
+        Some(job) => Ok(Json(job)),
+        None => Err(RouterError::NotFound(format!("job {id} not found"))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/iam/alive",
+    tag = "health",
+    responses((status = 200, description = "The service is alive", body = String))
+)]
 pub async fn alive() -> Result<&'static str, RouterError> {
     Ok("200")
 }
 
+///http route reporting the readiness of each kratos backend, the service is ready when all the
+///backends are
+#[utoipa::path(
+    get,
+    path = "/api/iam/ready",
+    tag = "health",
+    responses(
+        (status = 200, description = "State of each backend", body = BTreeMap<String, String>),
+        (
+            status = 503,
+            description = "A backend is not ready or the service is shutting down",
+            body = BTreeMap<String, String>
+        )
+    )
+)]
 pub async fn ready(
-    State(config): State<Arc<RwLock<IamConfig>>>,
-) -> Result<&'static str, RouterError> {
-    let config = config.read().await;
-    let client = match &config.kratos.client {
-        Some(client) => client,
-        None => Err(anyhow!("Kratos client not initialized"))?,
-    };
-    is_ready(client).await?;
-    Ok("200")
+    State(config): State<SharedConfig>,
+    State(shutdown): State<ShutdownCoordinator>,
+) -> Result<Json<BTreeMap<String, String>>, RouterError> {
+    if shutdown.is_draining() {
+        return Err(RouterError::ShuttingDown);
+    }
+    let config = config.load_full();
+    let checks = config.clients().map(|(name, client)| async move {
+        let status = match client.is_ready().await {
+            Ok(()) => "ready".to_owned(),
+            Err(e) => format!("not ready: {e:#}"),
+        };
+        (name.to_owned(), status)
+    });
+    let backends: BTreeMap<String, String> = join_all(checks).await.into_iter().collect();
+    if backends.is_empty() {
+        return Err(RouterError::Kratos(anyhow!(
+            "Kratos client not initialized"
+        )));
+    }
+    if backends.values().any(|status| status != "ready") {
+        return Err(RouterError::NotReady(backends));
+    }
+    Ok(Json(backends))
+}
+
+///http route returning the running config with the secrets redacted and the status of its
+///reloads
+#[utoipa::path(
+    get,
+    path = "/api/iam/config",
+    tag = "health",
+    responses(
+        (status = 200, description = "Running config with the secrets redacted", body = Object)
+    )
+)]
+pub async fn running_config(
+    State(config): State<SharedConfig>,
+    State(reload): State<ReloadStatus>,
+) -> Json<Value> {
+    let config = config.load();
+    let reload = reload.get();
+    Json(json!({
+        "paths": config.paths(),
+        "loaded_at": reload.loaded_at,
+        "last_error": reload.last_error,
+        "restart_required": reload.restart_required,
+        "config": config.redacted(),
+    }))
 }
 
 #[cfg(test)]
 mod http_router_test {
     use std::sync::Arc;
 
+    use arc_swap::ArcSwap;
+
     use crate::{
         app,
         config::{IamConfig, CONFIG_FALLBACK},
+        health,
+        state::AppState,
     };
     use axum::{
         body::Body,
@@ -103,11 +528,12 @@ mod http_router_test {
     };
     use rs_utils::config::Config;
     use serde_json::json;
-    use tokio::sync::RwLock;
     use tower::ServiceExt;
 
-    async fn create_config() -> Arc<RwLock<IamConfig>> {
-        Arc::new(RwLock::new(IamConfig::new(CONFIG_FALLBACK).await))
+    async fn create_config() -> AppState {
+        AppState::new(Arc::new(ArcSwap::from_pointee(
+            IamConfig::new(CONFIG_FALLBACK).await,
+        )))
     }
 
     #[tokio::test]
@@ -196,4 +622,198 @@ mod http_router_test {
 
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_modes() {
+        let config = create_config().await;
+        for (mode, current) in [(0, "owner"), (1, "viewer"), (2, "member")] {
+            for (method, resource, value, permissions) in [
+                (
+                    http::Method::POST,
+                    "222",
+                    json!("editor"),
+                    json!({"111": current, "222": "editor"}),
+                ),
+                (http::Method::DELETE, "111", json!(null), json!({})),
+            ] {
+                let response = app(config.clone())
+                    .oneshot(
+                        Request::builder()
+                            .method(method)
+                            .uri("/api/iam/policy")
+                            .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                            .body(Body::from(
+                                serde_json::to_string(&json!({
+                                  "id": "modes",
+                                  "perm_type": "project",
+                                  "resource": resource,
+                                  "value": "\"editor\"",
+                                  "mode": mode,
+                                }))
+                                .unwrap(),
+                            ))
+                            .unwrap(),
+                    )
+                    .await
+                    .unwrap();
+
+                assert_eq!(response.status(), StatusCode::OK);
+                let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                    .await
+                    .unwrap();
+                let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+                assert_eq!(
+                    body,
+                    json!({"id": "modes", "value": value, "permissions": permissions}),
+                    "mode {mode}"
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let config = create_config().await;
+        for (content_type, body) in [
+            (
+                "application/json",
+                r#"{"identities": {"target": {"metadata_admin": {"group": ["2", "3"]}}}}"#,
+            ),
+            (
+                "application/yaml",
+                "identities:\n  target:\n    metadata_admin:\n      group: [\"2\", \"3\"]\n",
+            ),
+        ] {
+            let response = app(config.clone())
+                .oneshot(
+                    Request::builder()
+                        .method(http::Method::POST)
+                        .uri("/api/iam/reconcile?apply=true")
+                        .header(http::header::CONTENT_TYPE, content_type)
+                        .body(Body::from(body))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+
+            assert_eq!(response.status(), StatusCode::OK, "{content_type}");
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+                .await
+                .unwrap();
+            let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                body,
+                json!({
+                    "applied": true,
+                    "identities": [{
+                        "id": "target",
+                        "patch": [{"op": "add", "path": "/metadata_admin/group/-", "value": "3"}],
+                        "applied": true,
+                    }]
+                }),
+                "{content_type}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_import_body_limit() {
+        let state = create_config().await;
+        let mut config = IamConfig::clone(&state.config.load());
+        config.rate_limit.max_body = 16;
+        state.config.store(Arc::new(config));
+        let response = app(state)
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/api/iam/import?dry_run=true")
+                    .header(http::header::CONTENT_TYPE, "application/x-ndjson")
+                    .body(Body::from(r#"{"id": "target", "metadata_admin": {}}"#))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[tokio::test]
+    async fn test_job_not_found() {
+        let config = create_config().await;
+        let app = app(config);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/api/iam/job/unknown")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_dry_run() {
+        let config = create_config().await;
+        let app = app(config);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::POST)
+                    .uri("/api/iam/policy")
+                    .header(http::header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .body(Body::from(
+                        serde_json::to_string(&json!({
+                          "id": "target",
+                          "perm_type": "project",
+                          "resource": "222",
+                          "value": "\"contributor\"",
+                          "mode": 0,
+                          "dry_run": true,
+                        }))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["preview"]["project"]["222"], "contributor");
+    }
+
+    #[tokio::test]
+    async fn test_running_config() {
+        let config = create_config().await;
+        let app = health(config);
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(http::Method::GET)
+                    .uri("/api/iam/config")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["paths"][0], CONFIG_FALLBACK);
+        assert!(body["config"]["kratos"]["addr"].is_string());
+        assert!(body["last_error"].is_null());
+    }
 }
@@ -0,0 +1,130 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use tokio::sync::RwLock;
+
+use crate::permission::{Job, JobState};
+
+///Time a finished job is kept in the registry.
+const FINISHED_TTL: Duration = Duration::from_secs(3600);
+///Number of finished jobs kept in the registry, the oldest ones are removed first.
+const MAX_FINISHED: usize = 1000;
+
+///Job of the registry, with the time it finished at.
+#[derive(Debug)]
+struct Entry {
+    job: Job,
+    finished: Option<Instant>,
+}
+
+///Registry of the background jobs launched by the service, it is shared between the http and
+///grpc routers so a job can be followed from either of them. The finished jobs are removed after
+///`FINISHED_TTL` or when there are more than `MAX_FINISHED` of them.
+#[derive(Clone, Default, Debug)]
+pub struct JobRegistry {
+    jobs: Arc<RwLock<HashMap<String, Entry>>>,
+}
+
+impl JobRegistry {
+    ///Register a new running job with a generated id and return its initial state.
+    pub async fn create(&self, dry_run: bool) -> Job {
+        let job = Job {
+            id: uuid::Uuid::new_v4().to_string(),
+            state: JobState::Running.into(),
+            dry_run,
+            ..Default::default()
+        };
+        let mut jobs = self.jobs.write().await;
+        evict(&mut jobs, Instant::now());
+        let entry = Entry {
+            job: job.clone(),
+            finished: None,
+        };
+        jobs.insert(job.id.clone(), entry);
+        job
+    }
+
+    ///Get the current state of a job.
+    pub async fn get(&self, id: &str) -> Option<Job> {
+        self.jobs
+            .read()
+            .await
+            .get(id)
+            .map(|entry| entry.job.clone())
+    }
+
+    ///Apply the given modification to a job, do nothing if the job is unknown.
+    pub async fn update<F: FnOnce(&mut Job)>(&self, id: &str, f: F) {
+        if let Some(entry) = self.jobs.write().await.get_mut(id) {
+            f(&mut entry.job);
+            if entry.finished.is_none() && entry.job.state() != JobState::Running {
+                entry.finished = Some(Instant::now());
+            }
+        }
+    }
+}
+
+///Remove the finished jobs older than `FINISHED_TTL` and the oldest ones above `MAX_FINISHED`.
+fn evict(jobs: &mut HashMap<String, Entry>, now: Instant) {
+    jobs.retain(|_, entry| match entry.finished {
+        Some(finished) => now.duration_since(finished) < FINISHED_TTL,
+        None => true,
+    });
+    let mut finished: Vec<(Instant, String)> = jobs
+        .iter()
+        .filter_map(|(id, entry)| Some((entry.finished?, id.clone())))
+        .collect();
+    if finished.len() <= MAX_FINISHED {
+        return;
+    }
+    finished.sort();
+    for (_, id) in &finished[..finished.len() - MAX_FINISHED] {
+        jobs.remove(id);
+    }
+}
+
+#[cfg(test)]
+mod test_job {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_registry() {
+        let jobs = JobRegistry::default();
+        let job = jobs.create(true).await;
+        assert_eq!(job.state(), JobState::Running);
+        assert_ne!(jobs.create(true).await.id, job.id);
+        jobs.update(&job.id, |job| {
+            job.scanned += 1;
+            job.set_state(JobState::Done);
+        })
+        .await;
+        let job = jobs.get(&job.id).await.unwrap();
+        assert_eq!(job.scanned, 1);
+        assert_eq!(job.state(), JobState::Done);
+        assert!(jobs.get("unknown").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_evict() {
+        let jobs = JobRegistry::default();
+        let running = jobs.create(false).await;
+        let mut finished = Vec::new();
+        for _ in 0..MAX_FINISHED + 1 {
+            let job = jobs.create(false).await;
+            jobs.update(&job.id, |job| job.set_state(JobState::Done))
+                .await;
+            finished.push(job.id);
+        }
+        jobs.create(false).await;
+        // the oldest finished job is removed, the running ones are kept
+        assert!(jobs.get(&finished[0]).await.is_none());
+        assert!(jobs.get(&finished[1]).await.is_some());
+        assert!(jobs.get(&running.id).await.is_some());
+        let mut entries = jobs.jobs.write().await;
+        evict(&mut entries, Instant::now() + FINISHED_TTL);
+        assert_eq!(entries.len(), 2);
+    }
+}
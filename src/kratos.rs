@@ -0,0 +1,647 @@
+use std::{
+    cmp::Ordering,
+    fmt::Debug,
+    future::Future,
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use rand::Rng;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use thiserror::Error;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tracing::{error, warn};
+
+#[allow(unused_imports)]
+use ory_kratos_client::{
+    apis::{configuration::Configuration, identity_api, metadata_api, Error, ResponseContent},
+    models::{Identity, JsonPatch},
+};
+#[cfg(test)]
+use serde_json::json;
+
+use crate::{
+    cache::IdentityCache,
+    config::{Cache, KratosAuth, Resilience},
+};
+
+///Number of identities requested to kratos per page when scanning all the identities.
+pub const PAGE_SIZE: i64 = 250;
+
+///Longest delay between two attempts of a kratos request, in milliseconds.
+const MAX_BACKOFF: u64 = 30_000;
+
+///Error raised without contacting kratos while the circuit breaker is open.
+#[derive(Error, Debug)]
+#[error("kratos is unavailable, retrying in {0:?}")]
+pub struct KratosUnavailable(pub Duration);
+
+///Error raised when a request selects a backend missing from the config.
+#[derive(Error, Debug)]
+#[error("unknown kratos backend {0}")]
+pub struct UnknownBackend(pub String);
+
+///Error raised when kratos does not know the requested identity.
+#[derive(Error, Debug)]
+#[error("identity {0} not found")]
+pub struct IdentityNotFound(pub String);
+
+///Error raised when no kratos request slot was freed before the request timeout.
+#[derive(Error, Debug)]
+#[error("too many concurrent kratos requests")]
+pub struct KratosBusy;
+
+#[derive(Debug, Default)]
+struct BreakerState {
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+///Circuit breaker opened after too many consecutive kratos failures, while it is open the
+///requests fail fast. Once the cooldown is elapsed requests are let through again and the
+///breaker is closed by the first success or reopened by the next failure.
+#[derive(Debug, Default)]
+struct Breaker {
+    state: Mutex<BreakerState>,
+}
+
+impl Breaker {
+    fn check(&self) -> Result<(), KratosUnavailable> {
+        let state = self.state.lock().expect("breaker lock poisoned");
+        match state.open_until {
+            Some(until) if until > Instant::now() => Err(KratosUnavailable(until - Instant::now())),
+            _ => Ok(()),
+        }
+    }
+
+    fn success(&self) {
+        *self.state.lock().expect("breaker lock poisoned") = BreakerState::default();
+    }
+
+    fn failure(&self, resilience: &Resilience) {
+        let mut state = self.state.lock().expect("breaker lock poisoned");
+        state.failures += 1;
+        if state.failures >= resilience.breaker_threshold {
+            warn!(
+                "{} consecutive kratos failures, opening the circuit breaker",
+                state.failures
+            );
+            state.open_until =
+                Some(Instant::now() + Duration::from_secs(resilience.breaker_cooldown));
+        }
+    }
+}
+
+///Limit of the concurrent kratos requests, it is shared by the successive clients of a kratos
+///and resized to the configured limit on each reload.
+#[derive(Debug)]
+struct Concurrency {
+    semaphore: Arc<Semaphore>,
+    limit: Mutex<usize>,
+}
+
+impl Concurrency {
+    fn new(limit: usize) -> Self {
+        Concurrency {
+            semaphore: Arc::new(Semaphore::new(limit)),
+            limit: Mutex::new(limit),
+        }
+    }
+
+    fn limit(&self) -> usize {
+        *self.limit.lock().expect("concurrency lock poisoned")
+    }
+
+    ///Resize the limit, the slots above a lowered limit are removed once the requests using
+    ///them are done.
+    fn resize(&self, limit: usize) {
+        let mut current = self.limit.lock().expect("concurrency lock poisoned");
+        match limit.cmp(&current) {
+            Ordering::Greater => self.semaphore.add_permits(limit - *current),
+            Ordering::Less => {
+                let semaphore = self.semaphore.clone();
+                let excess = u32::try_from(*current - limit).unwrap_or(u32::MAX);
+                tokio::spawn(async move {
+                    // the semaphore is never closed
+                    if let Ok(permits) = semaphore.acquire_many_owned(excess).await {
+                        permits.forget();
+                    }
+                });
+            }
+            Ordering::Equal => (),
+        }
+        *current = limit;
+    }
+}
+
+///Tell if a kratos error is worth retrying: connection errors, timeouts and 5xx responses. The
+///other request errors, like an invalid url, would fail again.
+fn is_transient<T>(e: &Error<T>) -> bool {
+    match e {
+        Error::Reqwest(e) => e.is_connect() || e.is_timeout(),
+        Error::ResponseError(resp) => resp.status.is_server_error(),
+        _ => false,
+    }
+}
+
+///Delay before retrying a request after `attempt` retries: the backoff doubled at each retry,
+///capped to `MAX_BACKOFF`.
+fn backoff_delay(backoff: u64, attempt: u32) -> u64 {
+    2u64.checked_pow(attempt)
+        .and_then(|factor| backoff.checked_mul(factor))
+        .unwrap_or(MAX_BACKOFF)
+        .min(MAX_BACKOFF)
+}
+
+///Kratos admin api client with timeouts, retries, a circuit breaker, an optional identity
+///cache and an optional limit of concurrent requests.
+#[derive(Clone, Debug)]
+pub struct KratosClient {
+    pub configuration: Configuration,
+    resilience: Resilience,
+    breaker: Arc<Breaker>,
+    cache: Option<Arc<IdentityCache>>,
+    concurrency: Option<Arc<Concurrency>>,
+}
+
+///Build the http client used for the kratos requests.
+fn http_client(resilience: &Resilience, headers: HeaderMap) -> Result<reqwest::Client> {
+    Ok(reqwest::Client::builder()
+        .timeout(Duration::from_millis(resilience.timeout))
+        .connect_timeout(Duration::from_millis(resilience.connect_timeout))
+        .default_headers(headers)
+        .build()?)
+}
+
+impl KratosClient {
+    ///Wrap the kratos configuration, its http client is rebuilt with the configured timeouts.
+    pub fn new(mut configuration: Configuration, resilience: Resilience) -> Result<Self> {
+        configuration.client = http_client(&resilience, HeaderMap::new())?;
+        Ok(KratosClient {
+            configuration,
+            resilience,
+            breaker: Arc::default(),
+            cache: None,
+            concurrency: None,
+        })
+    }
+
+    ///Keep the state of the client it replaces on a reload, the circuit breaker, the request
+    ///slots and the identity cache, unless its settings changed, of a kratos are shared by the
+    ///successive clients.
+    pub fn inherit(mut self, previous: &KratosClient) -> Self {
+        if self.configuration.base_path != previous.configuration.base_path {
+            return self;
+        }
+        self.breaker = previous.breaker.clone();
+        if let (Some(cache), Some(shared)) = (&self.cache, &previous.cache) {
+            if shared.has_settings(cache.capacity(), cache.ttl()) {
+                self.cache = previous.cache.clone();
+            }
+        }
+        if let (Some(concurrency), Some(shared)) = (&self.concurrency, &previous.concurrency) {
+            shared.resize(concurrency.limit());
+            self.concurrency = previous.concurrency.clone();
+        }
+        self
+    }
+
+    ///Send the token in the configured header of every kratos request, only the http client is
+    ///rebuilt and the other state of the client is kept.
+    pub fn with_auth(mut self, auth: &KratosAuth, token: &str) -> Result<Self> {
+        let value = match auth.prefix.is_empty() {
+            true => token.to_owned(),
+            false => format!("{} {token}", auth.prefix),
+        };
+        let mut value = HeaderValue::from_str(&value)?;
+        value.set_sensitive(true);
+        let mut headers = HeaderMap::new();
+        headers.insert(HeaderName::from_bytes(auth.header.as_bytes())?, value);
+        self.configuration.client = http_client(&self.resilience, headers)?;
+        Ok(self)
+    }
+
+    ///Limit the number of concurrent kratos requests, there is no limit if it is 0.
+    pub fn with_concurrency(mut self, limit: usize) -> Self {
+        self.concurrency = (limit > 0).then(|| Arc::new(Concurrency::new(limit)));
+        self
+    }
+
+    ///Wait for a free request slot, at most the request timeout.
+    async fn permit(&self) -> Result<Option<SemaphorePermit<'_>>> {
+        let Some(concurrency) = &self.concurrency else {
+            return Ok(None);
+        };
+        let timeout = Duration::from_millis(self.resilience.timeout);
+        match tokio::time::timeout(timeout, concurrency.semaphore.acquire()).await {
+            Ok(permit) => Ok(Some(permit?)),
+            Err(_) => Err(KratosBusy.into()),
+        }
+    }
+
+    ///Cache the fetched identities, nothing is cached if the cache capacity is 0.
+    pub fn with_cache(mut self, cache: &Cache) -> Self {
+        self.cache = NonZeroUsize::new(cache.capacity)
+            .map(|capacity| Arc::new(IdentityCache::new(capacity, Duration::from_secs(cache.ttl))));
+        self
+    }
+
+    ///Remove an identity from the cache.
+    pub fn invalidate(&self, id: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(id);
+        }
+    }
+
+    ///Run a kratos request through the circuit breaker, the idempotent requests are retried
+    ///with a jittered exponential backoff on transient errors.
+    async fn call<T, E, F, Fut>(&self, idempotent: bool, request: F) -> Result<T>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error<E>>>,
+        E: Debug + Send + Sync + 'static,
+    {
+        let mut attempt = 0;
+        loop {
+            self.breaker.check()?;
+            let permit = self.permit().await?;
+            let result = request().await;
+            drop(permit);
+            let e = match result {
+                Ok(resp) => {
+                    self.breaker.success();
+                    return Ok(resp);
+                }
+                Err(e) => e,
+            };
+            if !is_transient(&e) {
+                return Err(e.into());
+            }
+            self.breaker.failure(&self.resilience);
+            if !idempotent || attempt >= self.resilience.retries {
+                error!("kratos request failed after {} attempts: {e}", attempt + 1);
+                return Err(e.into());
+            }
+            let backoff = backoff_delay(self.resilience.backoff, attempt);
+            let jitter = rand::thread_rng().gen_range(0..=backoff / 2);
+            warn!(
+                "kratos request failed, retrying in {}ms: {e}",
+                backoff + jitter
+            );
+            tokio::time::sleep(Duration::from_millis(backoff + jitter)).await;
+            attempt += 1;
+        }
+    }
+
+    ///Fetch an identity, from the cache if it is enabled. Only for reading or checking the
+    ///permissions, a patch must be built from `fetch_identity`.
+    pub async fn get_identity(&self, id: &str) -> Result<Identity> {
+        if let Some(identity) = self.cache.as_ref().and_then(|cache| cache.get(id)) {
+            return Ok(identity);
+        }
+        self.fetch_identity(id).await
+    }
+
+    ///Fetch an identity from kratos bypassing the cache, the cache is refreshed with it unless
+    ///the identity was patched meanwhile.
+    pub async fn fetch_identity(&self, id: &str) -> Result<Identity> {
+        let generation = self.cache.as_ref().map(|cache| cache.generation());
+        #[cfg(not(test))]
+        let identity = self
+            .call(true, || {
+                identity_api::get_identity(&self.configuration, id, None)
+            })
+            .await
+            .map_err(
+                |e| match e.downcast_ref::<Error<identity_api::GetIdentityError>>() {
+                    Some(Error::ResponseError(resp)) if resp.status.as_u16() == 404 => {
+                        IdentityNotFound(id.to_owned()).into()
+                    }
+                    _ => e,
+                },
+            )?;
+        #[cfg(test)]
+        let identity = match id {
+            "unknown" => return Err(IdentityNotFound(id.to_owned()).into()),
+            _ => fixture(id),
+        };
+        if let (Some(cache), Some(generation)) = (&self.cache, generation) {
+            cache.insert(id, &identity, generation);
+        }
+        Ok(identity)
+    }
+
+    ///Fetch a page of identities, the first one without `page_token`, and return the token of
+    ///the next page if there is one. The pages are keyset paginated so the identities changed
+    ///during the scan are neither missed nor repeated. The generated client drops the `Link`
+    ///header giving the next page, the request is sent directly.
+    pub async fn list_identities(
+        &self,
+        page_token: Option<&str>,
+    ) -> Result<(Vec<Identity>, Option<String>)> {
+        #[cfg(not(test))]
+        let page = self
+            .call(true, || async {
+                let url = self.configuration.base_path.clone() + "/admin/identities";
+                let mut request = self
+                    .configuration
+                    .client
+                    .get(url)
+                    .query(&[("page_size", PAGE_SIZE)]);
+                if let Some(page_token) = page_token {
+                    request = request.query(&[("page_token", page_token)]);
+                }
+                let resp = request.send().await.map_err(Error::Reqwest)?;
+                let status = resp.status();
+                let next = resp
+                    .headers()
+                    .get(reqwest::header::LINK)
+                    .and_then(|link| link.to_str().ok())
+                    .and_then(next_page_token);
+                let content = resp.text().await.map_err(Error::Reqwest)?;
+                if !status.is_success() {
+                    return Err(Error::ResponseError(ResponseContent {
+                        status,
+                        content,
+                        entity: None::<identity_api::ListIdentitiesError>,
+                    }));
+                }
+                let identities: Vec<Identity> =
+                    serde_json::from_str(&content).map_err(Error::Serde)?;
+                Ok((identities, next))
+            })
+            .await?;
+        #[cfg(test)]
+        let page = match page_token {
+            None => (vec![fixture("owner")], Some("other".to_owned())),
+            Some(_) => (vec![fixture("other")], None),
+        };
+        Ok(page)
+    }
+
+    ///Apply a json patch to an identity and return the patched identity, a patch is not
+    ///idempotent so it is never retried. The identity is removed from the cache.
+    pub async fn patch_identity(&self, id: &str, patch: Vec<JsonPatch>) -> Result<Identity> {
+        #[cfg(not(test))]
+        let identity = self
+            .call(false, || {
+                identity_api::patch_identity(&self.configuration, id, Some(patch.clone()))
+            })
+            .await;
+        #[cfg(test)]
+        let identity = apply_fixture(fixture(id), &patch);
+        // the patch may have been applied even if the response was lost
+        self.invalidate(id);
+        identity
+    }
+
+    ///Check that kratos is ready with a single request, it does not wait for a request slot and
+    ///is not retried so a busy kratos client does not fail the probe or delay it.
+    pub async fn is_ready(&self) -> Result<()> {
+        metadata_api::is_ready(&self.configuration).await?;
+        Ok(())
+    }
+}
+
+///Extract the token of the next page from the `Link` header of a page of identities.
+fn next_page_token(link: &str) -> Option<String> {
+    let next = link.split(',').find(|link| link.contains("rel=\"next\""))?;
+    let url = next.split_once('<')?.1.split_once('>')?.0;
+    reqwest::Url::parse(url)
+        .ok()?
+        .query_pairs()
+        .find(|(key, _)| key == "page_token")
+        .map(|(_, token)| token.into_owned())
+}
+
+///Identity returned by the mocked kratos calls.
+#[cfg(test)]
+fn fixture(id: &str) -> Identity {
+    let mut identity = Identity::new(id.to_owned(), "test".to_owned(), "test".to_owned(), None);
+    identity.metadata_admin = match id {
+        "source" => Some(json!({
+            "project": {"222": "owner", "333": "viewer"},
+            "group": ["1"]
+        })),
+        "target" => Some(json!({"project": {"111": "owner"}, "group": ["2"]})),
+        "owner" => Some(json!({"project": {"222": "owner"}, "group": ["222"]})),
+        "other" => None,
+        "modes" => Some(json!({"project": {"111": "owner"}})),
+        _ => Some(json!({"project": {"222": "owner"}})),
+    };
+    if id == "modes" {
+        identity.metadata_public = Some(json!({"project": {"111": "viewer"}}));
+        identity.traits = Some(json!({"email": "modes@test.com", "project": {"111": "member"}}));
+    }
+    identity
+}
+
+///Apply a json patch to a fixture like kratos would.
+#[cfg(test)]
+fn apply_fixture(identity: Identity, patch: &[JsonPatch]) -> Result<Identity> {
+    let mut identity = serde_json::to_value(identity)?;
+    let patch = serde_json::from_value::<json_patch::Patch>(serde_json::to_value(patch)?)?;
+    json_patch::patch(&mut identity, &patch)?;
+    Ok(serde_json::from_value(identity)?)
+}
+
+impl Default for KratosClient {
+    fn default() -> Self {
+        KratosClient::new(Configuration::default(), Resilience::default())
+            .expect("failed to build the default kratos client")
+    }
+}
+
+#[cfg(test)]
+mod test_kratos {
+    use axum::{
+        http::{HeaderMap as AxumHeaderMap, StatusCode},
+        routing::get,
+        Json,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_breaker() {
+        let resilience = Resilience {
+            retries: 1,
+            backoff: 1,
+            breaker_threshold: 2,
+            ..Default::default()
+        };
+        let mut configuration = Configuration::new();
+        // nothing listens on the discard port
+        configuration.base_path = "http://127.0.0.1:9".to_owned();
+        let client = KratosClient::new(configuration, resilience).unwrap();
+        let ready = || client.call(true, || metadata_api::is_ready(&client.configuration));
+        let e = ready().await.unwrap_err();
+        assert!(e.downcast_ref::<KratosUnavailable>().is_none());
+        let e = ready().await.unwrap_err();
+        assert!(e.downcast_ref::<KratosUnavailable>().is_some());
+        // the readiness probe still reaches kratos
+        let e = client.is_ready().await.unwrap_err();
+        assert!(e.downcast_ref::<KratosUnavailable>().is_none());
+    }
+
+    #[test]
+    fn test_next_page_token() {
+        let link = concat!(
+            "<http://kratos:4434/admin/identities?page_size=250&page_token=first>; rel=\"first\",",
+            "<http://kratos:4434/admin/identities?page_size=250&page_token=a%2Bb>; rel=\"next\""
+        );
+        assert_eq!(next_page_token(link).unwrap(), "a+b");
+        let link = "<http://kratos:4434/admin/identities?page_token=first>; rel=\"first\"";
+        assert!(next_page_token(link).is_none());
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        assert_eq!(backoff_delay(100, 0), 100);
+        assert_eq!(backoff_delay(100, 3), 800);
+        assert_eq!(backoff_delay(100, 20), MAX_BACKOFF);
+        assert_eq!(backoff_delay(100, 64), MAX_BACKOFF);
+        assert_eq!(backoff_delay(u64::MAX, 1), MAX_BACKOFF);
+    }
+
+    #[test]
+    fn test_inherit() {
+        let resilience = Resilience {
+            breaker_threshold: 1,
+            ..Default::default()
+        };
+        let previous = KratosClient::new(Configuration::new(), resilience.clone()).unwrap();
+        previous.breaker.failure(&resilience);
+        let client = KratosClient::new(Configuration::new(), resilience.clone()).unwrap();
+        assert!(client.inherit(&previous).breaker.check().is_err());
+        let mut configuration = Configuration::new();
+        configuration.base_path = "http://kratos-eu:4434".to_owned();
+        let client = KratosClient::new(configuration, resilience).unwrap();
+        assert!(client.inherit(&previous).breaker.check().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_inherit_cache() {
+        let cache = Cache {
+            capacity: 10,
+            ..Default::default()
+        };
+        let previous = KratosClient::default().with_cache(&cache);
+        previous.get_identity("source").await.unwrap();
+        let client = KratosClient::default()
+            .with_cache(&cache)
+            .inherit(&previous);
+        assert!(client.cache.as_ref().unwrap().get("source").is_some());
+        // a rotated token keeps the cache
+        let client = client.with_auth(&KratosAuth::default(), "secret").unwrap();
+        assert!(client.cache.as_ref().unwrap().get("source").is_some());
+        let resized = Cache {
+            capacity: 20,
+            ..Default::default()
+        };
+        let client = KratosClient::default()
+            .with_cache(&resized)
+            .inherit(&client);
+        assert!(client.cache.as_ref().unwrap().get("source").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_auth() {
+        let app = axum::Router::new().route(
+            "/health/ready",
+            get(|headers: AxumHeaderMap| async move {
+                match headers.get("authorization").map(|value| value.as_bytes()) {
+                    Some(b"Bearer secret") => Ok(Json(json!({"status": "ok"}))),
+                    _ => Err(StatusCode::UNAUTHORIZED),
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut configuration = Configuration::new();
+        configuration.base_path = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async { axum::serve(listener, app).await });
+
+        let client = KratosClient::new(configuration, Resilience::default()).unwrap();
+        assert!(client.is_ready().await.is_err());
+        let client = client.with_auth(&KratosAuth::default(), "secret").unwrap();
+        client.is_ready().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_cache() {
+        let cache = Cache {
+            capacity: 10,
+            ..Default::default()
+        };
+        let client = KratosClient::default().with_cache(&cache);
+        let identity = client.get_identity("source").await.unwrap();
+        client
+            .cache
+            .as_ref()
+            .unwrap()
+            .insert("source", &Identity::default(), 0);
+        assert_ne!(client.get_identity("source").await.unwrap(), identity);
+        // the patches are built from a fresh identity
+        assert_eq!(client.fetch_identity("source").await.unwrap(), identity);
+        assert_eq!(client.get_identity("source").await.unwrap(), identity);
+        client
+            .cache
+            .as_ref()
+            .unwrap()
+            .insert("source", &Identity::default(), 0);
+        client.patch_identity("source", Vec::new()).await.unwrap();
+        assert_eq!(client.get_identity("source").await.unwrap(), identity);
+    }
+
+    #[tokio::test]
+    async fn test_ready_busy() {
+        let app = axum::Router::new().route(
+            "/health/ready",
+            get(|| async { Json(json!({"status": "ok"})) }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut configuration = Configuration::new();
+        configuration.base_path = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async { axum::serve(listener, app).await });
+
+        let client = KratosClient::new(configuration, Resilience::default())
+            .unwrap()
+            .with_concurrency(1);
+        let _permit = client.permit().await.unwrap();
+        client.is_ready().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_concurrency() {
+        let resilience = Resilience {
+            timeout: 10,
+            ..Default::default()
+        };
+        let client = KratosClient::new(Configuration::new(), resilience)
+            .unwrap()
+            .with_concurrency(1);
+        let _permit = client.permit().await.unwrap();
+        let e = client.permit().await.unwrap_err();
+        assert!(e.downcast_ref::<KratosBusy>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_resize() {
+        let previous = KratosClient::default().with_concurrency(1);
+        let client = KratosClient::default()
+            .with_concurrency(3)
+            .inherit(&previous);
+        let shared = previous.concurrency.as_ref().unwrap();
+        assert!(Arc::ptr_eq(shared, client.concurrency.as_ref().unwrap()));
+        assert_eq!(shared.semaphore.available_permits(), 3);
+        let permit = client.permit().await.unwrap();
+        let client = KratosClient::default().with_concurrency(1).inherit(&client);
+        drop(permit);
+        tokio::task::yield_now().await;
+        assert_eq!(shared.semaphore.available_permits(), 1);
+        assert_eq!(shared.limit(), 1);
+        assert!(client.concurrency.is_some());
+    }
+}
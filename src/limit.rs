@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use axum_server::{accept::Accept, tls_rustls::RustlsAcceptor};
+use openssl::{nid::Nid, x509::X509};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tonic::{service::Interceptor, transport::server::UdsConnectInfo, Status};
+use tower_http::add_extension::AddExtension;
+use tracing::warn;
+
+use crate::{
+    config::{RateLimit, SharedConfig},
+    http::error::RouterError,
+};
+
+///Number of callers tracked before the idle ones are forgotten.
+const MAX_CALLERS: usize = 10_000;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+///Token bucket rate limiter keyed by caller, the quotas are read from the config on each request
+///so a reload applies them immediately.
+#[derive(Clone, Default, Debug)]
+pub struct RateLimiter {
+    buckets: Arc<Mutex<HashMap<String, Bucket>>>,
+}
+
+impl RateLimiter {
+    ///Take a token from the caller bucket, return false if its quota is exhausted.
+    pub fn check(&self, caller: &str, limit: &RateLimit) -> bool {
+        let quota = limit.callers.get(caller).copied().unwrap_or(limit.requests);
+        if quota == 0 {
+            return true;
+        }
+        let quota = f64::from(quota);
+        let period = limit.period.max(1);
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("rate limiter lock poisoned");
+        if buckets.len() >= MAX_CALLERS && !buckets.contains_key(caller) {
+            // a bucket idle for a whole period is full again, like a new one
+            buckets.retain(|_, bucket| now.duration_since(bucket.updated).as_secs() < period);
+        }
+        let bucket = buckets.entry(caller.to_owned()).or_insert(Bucket {
+            tokens: quota,
+            updated: now,
+        });
+        let refill = now.duration_since(bucket.updated).as_secs_f64() * quota / period as f64;
+        bucket.tokens = (bucket.tokens + refill).min(quota);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+///Identity of the caller given by the common name of its client certificate.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity(pub Option<String>);
+
+///Get the common name of a der encoded certificate.
+fn common_name(der: &[u8]) -> Option<String> {
+    let cert = X509::from_der(der).ok()?;
+    let entry = cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()?;
+    entry.data().as_utf8().ok().map(|name| name.to_string())
+}
+
+///Tls acceptor adding the identity of the client certificate to the requests of the connection.
+#[derive(Clone)]
+pub struct CertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl CertAcceptor {
+    pub fn new(inner: RustlsAcceptor) -> Self {
+        CertAcceptor { inner }
+    }
+}
+
+impl<I, S> Accept<I, S> for CertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, ClientIdentity>;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(Self::Stream, Self::Service)>> + Send>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+            let identity = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| common_name(&cert.0));
+            Ok((stream, AddExtension::new(service, ClientIdentity(identity))))
+        })
+    }
+}
+
+///Get the caller of an http request: the certificate identity or the remote address.
+fn http_caller(request: &Request) -> String {
+    if let Some(ClientIdentity(Some(name))) = request.extensions().get::<ClientIdentity>() {
+        return name.clone();
+    }
+    match request.extensions().get::<ConnectInfo<SocketAddr>>() {
+        Some(ConnectInfo(addr)) => addr.ip().to_string(),
+        None => "unknown".to_owned(),
+    }
+}
+
+///Http middleware rejecting the requests of the callers over their quota.
+pub async fn rate_limit(
+    State(config): State<SharedConfig>,
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Result<Response, RouterError> {
+    let caller = http_caller(&request);
+    if !limiter.check(&caller, &config.load().rate_limit) {
+        return Err(RouterError::TooManyRequests(caller));
+    }
+    Ok(next.run(request).await)
+}
+
+///Get the identity of the peer of a grpc request: the identity added by a layer or the user id of
+///the unix socket peer.
+pub fn grpc_identity(extensions: &tonic::Extensions) -> Option<String> {
+    if let Some(ClientIdentity(Some(name))) = extensions.get::<ClientIdentity>() {
+        return Some(name.clone());
+    }
+    let cred = extensions.get::<UdsConnectInfo>()?.peer_cred?;
+    Some(format!("uid:{}", cred.uid()))
+}
+
+///Grpc interceptor rejecting the requests of the callers over their quota, the callers are
+///identified by their peer identity or else their remote address.
+#[derive(Clone)]
+pub struct RateLimitInterceptor {
+    pub config: SharedConfig,
+    pub limiter: RateLimiter,
+}
+
+impl Interceptor for RateLimitInterceptor {
+    fn call(&mut self, request: tonic::Request<()>) -> Result<tonic::Request<()>, Status> {
+        let caller = match (grpc_identity(request.extensions()), request.remote_addr()) {
+            (Some(identity), _) => identity,
+            (None, Some(addr)) => addr.ip().to_string(),
+            (None, None) => "unknown".to_owned(),
+        };
+        if !self.limiter.check(&caller, &self.config.load().rate_limit) {
+            warn!("rate limit exceeded for {caller}");
+            return Err(Status::resource_exhausted(format!(
+                "rate limit exceeded for {caller}"
+            )));
+        }
+        Ok(request)
+    }
+}
+
+#[cfg(test)]
+mod test_limit {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let limit = RateLimit {
+            requests: 2,
+            period: 60,
+            callers: HashMap::from([("job".to_owned(), 0)]),
+            ..Default::default()
+        };
+        let limiter = RateLimiter::default();
+        assert!(limiter.check("1.1.1.1", &limit));
+        assert!(limiter.check("1.1.1.1", &limit));
+        assert!(!limiter.check("1.1.1.1", &limit));
+        assert!(limiter.check("2.2.2.2", &limit));
+        for _ in 0..10 {
+            assert!(limiter.check("job", &limit));
+        }
+    }
+}
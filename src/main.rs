@@ -1,84 +1,172 @@
-use std::{future::IntoFuture, sync::Arc, time::Duration};
+use std::{future::IntoFuture, net::SocketAddr, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use arc_swap::ArcSwap;
 use axum::{
+    extract::DefaultBodyLimit,
+    middleware::from_fn_with_state,
     routing::{get, post},
     serve, Router,
 };
 use axum_server::{bind_rustls, tls_rustls::RustlsConfig, Handle};
-use tokio::{net::TcpListener, sync::RwLock, task::JoinHandle};
-use tonic::transport::Server as GrpcServer;
+use clap::Parser;
+use futures::{future::try_join_all, FutureExt, Stream};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_stream::wrappers::UnixListenerStream;
+use tonic::{
+    service::interceptor,
+    transport::{
+        server::{Connected, TcpIncoming},
+        Server as GrpcServer,
+    },
+};
+use tonic_reflection::server::Builder as ReflectionBuilder;
+use tonic_web::GrpcWebLayer;
+use tower::util::option_layer;
 use tower_http::request_id::{MakeRequestUuid, SetRequestIdLayer};
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 use tracing_subscriber::{fmt, EnvFilter};
 
-use rs_utils::config::{init_watcher, Config};
-
 pub mod permission {
     tonic::include_proto!("permission");
+
+    ///Encoded descriptors of the proto files, served by the grpc reflection.
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        tonic::include_file_descriptor_set!("permission_descriptor");
 }
-use permission::iam_server::IamServer;
+use permission::{iam_server::IamServer, FILE_DESCRIPTOR_SET};
 mod mtls;
 use mtls::build_rustls_server_config;
 mod handler;
-use handler::{fallback, shutdown_signal};
+use handler::fallback;
 mod grpc;
 use grpc::router::MyIam;
 mod http;
-use http::router::{add, alive, ready, remove, replace};
+use http::{
+    openapi::openapi,
+    router::{
+        add, alive, clone, export, get_job, import, invalidate, purge, ready, reconcile, remove,
+        replace, running_config, schema,
+    },
+};
 mod config;
-use config::{IamConfig, Tls, CONFIG_FALLBACK};
-
-type ConfigState = Arc<RwLock<IamConfig>>;
+use config::{watch, IamConfig, Tls};
+mod backup;
+mod cache;
+mod cli;
+use cli::{run, Cli, Command};
+mod event;
+mod job;
+mod kratos;
+mod limit;
+use limit::{rate_limit, CertAcceptor, RateLimitInterceptor};
+mod reconcile;
+mod schema;
+mod shutdown;
+use shutdown::ShutdownCoordinator;
+mod state;
+use state::AppState;
+mod unix;
 
-///lauch the grpc router
-fn make_grpc(
-    shared_state: ConfigState,
-    addr: &str,
-) -> JoinHandle<Result<(), tonic::transport::Error>> {
+///lauch the grpc router on the incoming connections
+fn make_grpc<I, IO, IE>(
+    shared_state: AppState,
+    incoming: I,
+) -> Result<JoinHandle<Result<(), tonic::transport::Error>>>
+where
+    I: Stream<Item = Result<IO, IE>> + Send + 'static,
+    IO: AsyncRead + AsyncWrite + Connected + Unpin + Send + 'static,
+    IO::ConnectInfo: Clone + Send + Sync + 'static,
+    IE: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    let drain = shared_state.shutdown.clone();
+    let grpc_web = shared_state.config.load().grpc_web.clone();
+    let limiter = RateLimitInterceptor {
+        config: shared_state.config.clone(),
+        limiter: shared_state.limiter,
+    };
     let service = IamServer::new(MyIam {
-        config: shared_state,
+        config: shared_state.config,
+        jobs: shared_state.jobs,
+        events: shared_state.events,
+        shutdown: shared_state.shutdown,
     });
-    info!("lauching grpc server on: {addr}");
-    let socket = addr.parse().unwrap();
-    tokio::spawn(
+    // grpcurl and the other tools discover the services without the proto files
+    let reflection = ReflectionBuilder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build_v1()?;
+    let reflection_alpha = ReflectionBuilder::configure()
+        .register_encoded_file_descriptor_set(FILE_DESCRIPTOR_SET)
+        .build_v1alpha()?;
+    Ok(tokio::spawn(
         GrpcServer::builder()
+            // grpc-web is served on http1 to the browsers
+            .accept_http1(grpc_web.enabled)
             .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
+            .layer(option_layer(grpc_web.enabled.then(|| grpc_web.cors())))
+            .layer(option_layer(grpc_web.enabled.then(GrpcWebLayer::new)))
+            .layer(interceptor(limiter))
             .add_service(service)
-            .serve_with_shutdown(socket, shutdown_signal()),
-    )
+            .add_service(reflection)
+            .add_service(reflection_alpha)
+            .serve_with_incoming_shutdown(incoming, async move {
+                drain.drain_deadline().await;
+            }),
+    ))
 }
 
 ///main router config
-fn app(shared_state: ConfigState) -> Router {
+fn app(shared_state: AppState) -> Router {
     info!("configuring main router");
+    // the documents and exports may be larger than the default limit
+    let body_limit = DefaultBodyLimit::max(shared_state.config.load().rate_limit.max_body);
     Router::new()
         .route("/api/iam/policy", post(add).delete(remove).put(replace))
-        .with_state(shared_state)
+        .route("/api/iam/clone", post(clone))
+        .route("/api/iam/purge", post(purge))
+        .route(
+            "/api/iam/reconcile",
+            post(reconcile).layer(body_limit.clone()),
+        )
+        .route("/api/iam/export", get(export))
+        .route("/api/iam/import", post(import).layer(body_limit))
+        .route("/api/iam/schema", get(schema))
+        .route("/api/iam/job/:id", get(get_job))
+        .route("/api/iam/cache/invalidate", post(invalidate))
+        .route("/api/iam/openapi.json", get(openapi))
+        .with_state(shared_state.clone())
         .fallback(fallback)
+        .layer(from_fn_with_state(shared_state, rate_limit))
         .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid))
 }
 
 ///heatlh router config
-pub fn health(shared_state: ConfigState) -> Router {
+pub fn health(shared_state: AppState) -> Router {
     info!("configuring health router");
     Router::new()
         .route("/api/iam/alive", get(alive))
         .route("/api/iam/ready", get(ready))
+        .route("/api/iam/config", get(running_config))
+        .route("/api/iam/openapi.json", get(openapi))
         .fallback(fallback)
         .with_state(shared_state)
 }
 
-///this function send the shutdown signal to the router
-async fn shutdown(handle: axum_server::Handle) {
-    shutdown_signal().await;
-    handle.graceful_shutdown(Some(Duration::from_secs(30)));
+///this function drains the mtls router once the shutdown coordinator asks for it
+async fn shutdown(handle: axum_server::Handle, coordinator: ShutdownCoordinator) {
+    let deadline = coordinator.drain_deadline().await;
+    handle.graceful_shutdown(Some(deadline.saturating_duration_since(Instant::now())));
 }
 
 ///launch http router with mtls
 async fn make_http_mtls(
-    shared_state: ConfigState,
-    f: fn(ConfigState) -> Router,
+    shared_state: AppState,
+    f: fn(AppState) -> Router,
     addr: String,
     handle: &Handle,
     tls: &Tls,
@@ -87,11 +175,14 @@ async fn make_http_mtls(
     let tls_config =
         build_rustls_server_config(&tls.certificate, &tls.key, &tls.cert_autority).await?;
     let rustls_config = RustlsConfig::from_config(tls_config);
-    let addr = addr.parse().unwrap();
+    let addr = addr
+        .parse()
+        .with_context(|| format!("invalid http address {addr}"))?;
     let handle = tokio::spawn(
         bind_rustls(addr, rustls_config)
+            .map(CertAcceptor::new)
             .handle(handle.to_owned())
-            .serve(f(shared_state).into_make_service()),
+            .serve(f(shared_state).into_make_service_with_connect_info::<SocketAddr>()),
     );
     info!("lauching http server on: {addr}");
     Ok(handle)
@@ -99,52 +190,196 @@ async fn make_http_mtls(
 
 ///launch simple http router
 async fn make_http(
-    shared_state: ConfigState,
-    f: fn(ConfigState) -> Router,
+    shared_state: AppState,
+    f: fn(AppState) -> Router,
     addr: String,
-) -> JoinHandle<Result<(), std::io::Error>> {
+) -> Result<JoinHandle<Result<(), std::io::Error>>> {
     //todo: add path for tls certificate
-    let listener = TcpListener::bind(&addr).await.unwrap();
+    let listener = TcpListener::bind(&addr)
+        .await
+        .with_context(|| format!("cannot bind {addr}"))?;
+    let shutdown = shared_state.shutdown.clone();
     let service = serve(listener, f(shared_state))
-        .with_graceful_shutdown(shutdown_signal())
+        .with_graceful_shutdown(async move {
+            shutdown.drain_deadline().await;
+        })
         .into_future();
     info!("lauching http server on: {addr}");
-    tokio::spawn(service)
+    Ok(tokio::spawn(service))
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    fmt()
-        .with_target(false)
-        .with_level(true)
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
-    let config_path = std::env::var("CONFIG").unwrap_or_else(|_| {
-        warn!("Config variable not found switching to fallback");
-        CONFIG_FALLBACK.to_owned()
-    });
-    let config = IamConfig::new(&config_path).await;
+///wait for a server, the failure of any server stops the service
+async fn critical<E>(server: JoinHandle<Result<(), E>>) -> Result<()>
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    Ok(server.await??)
+}
+
+///launch the http, health and grpc servers
+async fn serve(config_paths: Vec<String>) -> Result<()> {
+    let config = IamConfig::load(&config_paths).await?;
+    config.validate_listeners().await?;
     let service = config.service.clone();
     let tls = config.tls.clone();
-    let shared_state = Arc::new(RwLock::new(config));
-    tokio::spawn(init_watcher(config_path, shared_state.clone(), None));
+    let shutdown_config = config.shutdown.clone();
+    let config = Arc::new(ArcSwap::from_pointee(config));
+    let shared_state = AppState::new(config.clone());
+    let reload = shared_state.reload.clone();
+    tokio::spawn(async move {
+        // the config is still served without reloads
+        if let Err(e) = watch(config_paths, config, reload.clone()).await {
+            error!("config watcher stopped, the config is no longer reloaded: {e:#}");
+            reload.failed(&e);
+        }
+    });
+    let coordinator = shared_state.shutdown.clone();
+    tokio::spawn(coordinator.clone().run(shutdown_config));
 
     let handle = Handle::new();
-    tokio::spawn(shutdown(handle.clone()));
+    tokio::spawn(shutdown(handle.clone(), coordinator.clone()));
 
-    info!("statrting http router");
-    let http_addr = service.addr.clone() + ":" + &service.ports.http as &str;
-    let http = make_http_mtls(shared_state.clone(), app, http_addr, &handle, &tls).await?;
+    let sockets = service.unix.clone();
+    // the socket files are removed once the servers are stopped
+    let mut socket_files = Vec::new();
+    let mut servers = Vec::new();
+    if !sockets.only {
+        info!("statrting http router");
+        let http_addr = service.addr.clone() + ":" + &service.ports.http as &str;
+        let http = make_http_mtls(shared_state.clone(), app, http_addr, &handle, &tls).await?;
+        servers.push(critical(http).boxed());
+    }
+    if let Some(path) = &sockets.http {
+        let (listener, socket_file) = unix::bind(path, sockets.mode)?;
+        socket_files.push(socket_file);
+        info!("lauching http server on: {}", path.display());
+        let http = unix::serve(listener, app(shared_state.clone()), coordinator.clone());
+        servers.push(critical(http).boxed());
+    }
 
     let health_addr = service.addr.clone() + ":" + &service.ports.http_health as &str;
-    let health = make_http(shared_state.clone(), health, health_addr).await;
+    let health = make_http(shared_state.clone(), health, health_addr).await?;
+    servers.push(critical(health).boxed());
 
     info!("statrting grpc router");
-    let grpc_addr = service.addr.clone() + ":" + &service.ports.grpc as &str;
-    let grpc = make_grpc(shared_state, &grpc_addr);
-    let (grpc_critical, http_critical, health_critical) = tokio::try_join!(grpc, http, health)?;
-    grpc_critical?;
-    http_critical?;
-    health_critical?;
-    Ok(())
+    if !sockets.only {
+        let grpc_addr = service.addr.clone() + ":" + &service.ports.grpc as &str;
+        let incoming = TcpIncoming::new(grpc_addr.parse()?, false, None)
+            .map_err(|e| anyhow!(e))
+            .with_context(|| format!("cannot bind {grpc_addr}"))?;
+        info!("lauching grpc server on: {grpc_addr}");
+        servers.push(critical(make_grpc(shared_state.clone(), incoming)?).boxed());
+    }
+    if let Some(path) = &sockets.grpc {
+        let (listener, socket_file) = unix::bind(path, sockets.mode)?;
+        socket_files.push(socket_file);
+        let incoming = UnixListenerStream::new(listener);
+        info!("lauching grpc server on: {}", path.display());
+        servers.push(critical(make_grpc(shared_state, incoming)?).boxed());
+    }
+    // the purge jobs and the queued events are given the rest of the drain
+    let drained = async {
+        try_join_all(servers).await?;
+        info!("waiting for the background tasks");
+        coordinator.tasks.idle().await;
+        anyhow::Ok(())
+    };
+    tokio::select! {
+        drained = drained => drained,
+        () = coordinator.deadline() => {
+            warn!("shutdown deadline reached, dropping the remaining connections and tasks");
+            Ok(())
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let subscriber = fmt()
+        .with_target(false)
+        .with_level(true)
+        .with_env_filter(EnvFilter::from_default_env());
+    match cli.command {
+        None | Some(Command::Serve) => {
+            subscriber.init();
+            serve(cli.config).await
+        }
+        // keep the standard output for the result of the command
+        Some(command) => {
+            subscriber.with_writer(std::io::stderr).init();
+            run(&cli.config, cli.backend.as_deref(), command).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_main {
+    use prost::Message;
+    use rs_utils::config::Config;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    use super::*;
+    use crate::{config::CONFIG_FALLBACK, permission::JobId};
+
+    #[tokio::test]
+    async fn test_grpc_web() {
+        let mut config = IamConfig::new(CONFIG_FALLBACK).await;
+        config.grpc_web.enabled = true;
+        config.grpc_web.allowed_origins = vec!["https://admin.example.com".to_owned()];
+        let state = AppState::new(Arc::new(ArcSwap::from_pointee(config)));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!(
+            "http://{}/permission.Iam/GetJob",
+            listener.local_addr().unwrap()
+        );
+        make_grpc(state, TcpListenerStream::new(listener)).unwrap();
+        let client = reqwest::Client::new();
+
+        for (origin, allowed) in [
+            ("https://admin.example.com", true),
+            ("https://other.example.com", false),
+        ] {
+            let preflight = client
+                .request(reqwest::Method::OPTIONS, &url)
+                .header("origin", origin)
+                .header("access-control-request-method", "POST")
+                .header("access-control-request-headers", "content-type,x-grpc-web")
+                .send()
+                .await
+                .unwrap();
+            assert!(preflight.status().is_success());
+            let allow_origin = preflight.headers().get("access-control-allow-origin");
+            assert_eq!(allow_origin.is_some_and(|value| value == origin), allowed);
+        }
+
+        let message = JobId {
+            id: "unknown".to_owned(),
+        }
+        .encode_to_vec();
+        let mut body = vec![0];
+        body.extend((message.len() as u32).to_be_bytes());
+        body.extend(message);
+        let response = client
+            .post(&url)
+            .header("origin", "https://admin.example.com")
+            .header("content-type", "application/grpc-web+proto")
+            .header("x-grpc-web", "1")
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        assert!(response.status().is_success());
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://admin.example.com"
+        );
+        // the status is in the headers of a trailers only response, else in the trailers frame
+        let status = response.headers().get("grpc-status").cloned();
+        let body = response.bytes().await.unwrap();
+        assert!(
+            status.is_some_and(|status| status == "5")
+                || String::from_utf8_lossy(&body).contains("grpc-status:5")
+        );
+    }
 }
@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
+
+use ory_kratos_client::models::{Identity, JsonPatch};
+
+use crate::{
+    http::controler::{escape_segment, mode_data},
+    kratos::{IdentityNotFound, KratosClient},
+    permission::Mode,
+    schema::{validate_patch, Schemas},
+};
+
+///Format of a declarative permission document.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DocumentFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl DocumentFormat {
+    ///Guess the format from a content type, json is used when it is not recognized.
+    pub fn from_content_type(content_type: &str) -> Self {
+        match content_type {
+            t if t.contains("toml") => DocumentFormat::Toml,
+            t if t.contains("yaml") => DocumentFormat::Yaml,
+            _ => DocumentFormat::Json,
+        }
+    }
+}
+
+///Declarative representation of the permissions of the identities, it maps the identities to
+///their modes (`metadata_admin`, `metadata_public` or `traits`), then to their perm_types and
+///finally to the resources and their values.
+///Only the declared perm_types are managed, the other data of the identities are left untouched.
+#[derive(Deserialize, Debug, Default)]
+pub struct Desired {
+    pub identities: BTreeMap<String, BTreeMap<String, Map<String, Value>>>,
+}
+
+impl Desired {
+    ///Parse a declarative document.
+    pub fn parse(content: &str, format: DocumentFormat) -> Result<Self> {
+        let desired = match format {
+            DocumentFormat::Toml => toml::from_str(content).map_err(anyhow::Error::from),
+            DocumentFormat::Yaml => serde_yaml::from_str(content).map_err(anyhow::Error::from),
+            DocumentFormat::Json => serde_json::from_str(content).map_err(anyhow::Error::from),
+        };
+        desired.context("invalid permission document")
+    }
+}
+
+///Patch needed to bring an identity to its desired state.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct IdentityPlan {
+    pub id: String,
+    ///Identity the patch was built from.
+    #[serde(skip)]
+    pub identity: Identity,
+    pub patch: Vec<Value>,
+    ///Whether the patch was applied to the identity.
+    pub applied: bool,
+    ///Error of a patch that failed to apply.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl IdentityPlan {
+    pub fn new(identity: Identity, patch: Vec<Value>) -> Self {
+        IdentityPlan {
+            id: identity.id.clone(),
+            identity,
+            patch,
+            applied: false,
+            error: None,
+        }
+    }
+}
+
+///Result of a reconciliation, the identities already in their desired state are omitted.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct Plan {
+    ///Whether the patches were asked to be applied, each identity tells if its patch was.
+    pub applied: bool,
+    pub identities: Vec<IdentityPlan>,
+    ///Ids of the identities missing from kratos, they are skipped.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unknown: Vec<String>,
+}
+
+///Get the mode corresponding to the root of the identity data.
+pub fn root_mode(root: &str) -> Result<Mode> {
+    match root {
+        "metadata_admin" => Ok(Mode::Admin),
+        "metadata_public" => Ok(Mode::Public),
+        "traits" => Ok(Mode::Trait),
+        _ => bail!("unknown mode {root}, expected metadata_admin, metadata_public or traits"),
+    }
+}
+
+///Compute the json patch operations needed to go from the current value of a perm_type to the
+///desired one.
+fn diff_perm_type(path: &str, current: Option<&Value>, desired: &Value, patch: &mut Vec<Value>) {
+    match (current, desired) {
+        (Some(current), desired) if current == desired => (),
+        (Some(Value::Object(current)), Value::Object(desired)) => {
+            for (resource, value) in desired {
+                let path = path.to_owned() + "/" + &escape_segment(resource);
+                match current.get(resource) {
+                    Some(old) if old == value => (),
+                    Some(_) => patch.push(json!({"op": "replace", "path": path, "value": value})),
+                    None => patch.push(json!({"op": "add", "path": path, "value": value})),
+                }
+            }
+            for resource in current.keys().filter(|key| !desired.contains_key(*key)) {
+                let path = path.to_owned() + "/" + &escape_segment(resource);
+                patch.push(json!({"op": "remove", "path": path}));
+            }
+        }
+        (Some(Value::Array(current)), Value::Array(desired)) => {
+            // remove from the end so the indexes of the remaining elements stay valid
+            for (index, _) in current
+                .iter()
+                .enumerate()
+                .rev()
+                .filter(|(_, value)| !desired.contains(value))
+            {
+                let path = path.to_owned() + "/" + &index.to_string();
+                patch.push(json!({"op": "remove", "path": path}));
+            }
+            for value in desired.iter().filter(|value| !current.contains(value)) {
+                let path = path.to_owned() + "/-";
+                patch.push(json!({"op": "add", "path": path, "value": value}));
+            }
+        }
+        _ => patch.push(json!({"op": "add", "path": path, "value": desired})),
+    }
+}
+
+///Compute the json patch operations needed to bring the identity to its desired state.
+pub fn diff(
+    identity: &Identity,
+    desired: &BTreeMap<String, Map<String, Value>>,
+) -> Result<Vec<Value>> {
+    let mut patch = Vec::new();
+    for (root, perm_types) in desired {
+        let (root, current) = mode_data(identity, root_mode(root)?);
+        let current = match current {
+            Some(Value::Object(current)) => Some(current),
+            _ => {
+                patch.push(json!({"op": "add", "path": "/".to_owned() + root, "value": {}}));
+                None
+            }
+        };
+        for (perm_type, value) in perm_types {
+            let path = "/".to_owned() + root + "/" + &escape_segment(perm_type);
+            let current = current.and_then(|current| current.get(perm_type));
+            diff_perm_type(&path, current, value, &mut patch);
+        }
+    }
+    Ok(patch)
+}
+
+///Diff the desired permissions against kratos and apply the resulting patches when asked, the
+///patches are checked against the perm_type schemas before any of them is applied.
+pub async fn reconcile(
+    client: &KratosClient,
+    schemas: &Schemas,
+    uuid: &str,
+    desired: &Desired,
+    apply: bool,
+) -> Result<Plan> {
+    let mut identities = Vec::new();
+    let mut unknown = Vec::new();
+    for (id, modes) in &desired.identities {
+        let Some(identity) = fetch_known(client, uuid, id, apply).await? else {
+            unknown.push(id.clone());
+            continue;
+        };
+        let patch = diff(&identity, modes)?;
+        validate_patch(schemas, &patch).context(format!("{uuid}: {id}"))?;
+        if !patch.is_empty() {
+            debug!("{uuid}: plan for {id}: {patch:?}");
+            identities.push(IdentityPlan::new(identity, patch));
+        }
+    }
+    info!(
+        "{uuid}: {} identities out of {} to reconcile",
+        identities.len(),
+        desired.identities.len()
+    );
+    if apply {
+        apply_plan(client, uuid, &mut identities).await;
+    }
+    Ok(Plan {
+        applied: apply,
+        identities,
+        unknown,
+    })
+}
+
+///Fetch an identity, `None` if kratos does not know it. Unless the identity is `fresh`, for a
+///patch to apply, it can come from the cache.
+pub async fn fetch_known(
+    client: &KratosClient,
+    uuid: &str,
+    id: &str,
+    fresh: bool,
+) -> Result<Option<Identity>> {
+    let identity = match fresh {
+        true => client.fetch_identity(id).await,
+        false => client.get_identity(id).await,
+    };
+    match identity {
+        Ok(identity) => Ok(Some(identity)),
+        Err(e) if e.downcast_ref::<IdentityNotFound>().is_some() => {
+            warn!("{uuid}: skipping the unknown identity {id}");
+            Ok(None)
+        }
+        Err(e) => Err(e.context(format!("{uuid}: {id}"))),
+    }
+}
+
+///Apply the patches of the identities, a failed patch does not stop the other ones and its
+///error is reported in the identity plan.
+pub async fn apply_plan(client: &KratosClient, uuid: &str, identities: &mut [IdentityPlan]) {
+    for plan in identities {
+        let patch = serde_json::from_value::<Vec<JsonPatch>>(Value::Array(plan.patch.clone()));
+        let patched = match patch {
+            Ok(patch) => client.patch_identity(&plan.id, patch).await.map(|_| ()),
+            Err(e) => Err(e.into()),
+        };
+        match patched {
+            Ok(()) => {
+                info!("{uuid}: {} patched", plan.id);
+                plan.applied = true;
+            }
+            Err(e) => {
+                error!("{uuid}: failed to patch {}: {e:#}", plan.id);
+                plan.error = Some(format!("{e:#}"));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_reconcile {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let desired = Desired::parse(
+            r#"
+            [identities.target.metadata_admin]
+            group = ["1"]
+            project = { "111" = "viewer", "222" = "owner" }
+            "#,
+            DocumentFormat::Toml,
+        )
+        .unwrap();
+        let plan = reconcile(
+            &KratosClient::default(),
+            &Schemas::default(),
+            "test",
+            &desired,
+            true,
+        )
+        .await
+        .unwrap();
+        assert!(plan.applied);
+        assert_eq!(plan.identities.len(), 1);
+        assert!(plan.identities[0].applied);
+        assert_eq!(
+            plan.identities[0].patch,
+            vec![
+                json!({"op": "remove", "path": "/metadata_admin/group/0"}),
+                json!({"op": "add", "path": "/metadata_admin/group/-", "value": "1"}),
+                json!({
+                    "op": "replace",
+                    "path": "/metadata_admin/project/111",
+                    "value": "viewer"
+                }),
+                json!({"op": "add", "path": "/metadata_admin/project/222", "value": "owner"}),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unknown_identity() {
+        let desired = Desired::parse(
+            r#"{"identities": {"unknown": {"metadata_admin": {"group": []}}}}"#,
+            DocumentFormat::Json,
+        )
+        .unwrap();
+        let client = KratosClient::default();
+        let plan = reconcile(&client, &Schemas::default(), "test", &desired, true)
+            .await
+            .unwrap();
+        assert!(plan.identities.is_empty());
+        assert_eq!(plan.unknown, vec!["unknown".to_owned()]);
+    }
+
+    #[tokio::test]
+    async fn test_apply_plan() {
+        let mut identities = vec![
+            IdentityPlan::new(
+                Identity {
+                    id: "target".to_owned(),
+                    ..Default::default()
+                },
+                vec![json!({"op": "remove", "path": "/metadata_admin/unknown"})],
+            ),
+            IdentityPlan::new(
+                Identity {
+                    id: "source".to_owned(),
+                    ..Default::default()
+                },
+                vec![json!({"op": "remove", "path": "/metadata_admin/group"})],
+            ),
+        ];
+        apply_plan(&KratosClient::default(), "test", &mut identities).await;
+        assert!(!identities[0].applied);
+        assert!(identities[0].error.is_some());
+        assert!(identities[1].applied);
+        assert!(identities[1].error.is_none());
+    }
+
+    #[test]
+    fn test_diff_escape() {
+        let identity = Identity {
+            metadata_admin: Some(json!({"repo": {"c~d": "owner"}})),
+            ..Default::default()
+        };
+        let desired = BTreeMap::from([(
+            "metadata_admin".to_owned(),
+            Map::from_iter([("repo".to_owned(), json!({"a/b": "owner"}))]),
+        )]);
+        assert_eq!(
+            diff(&identity, &desired).unwrap(),
+            vec![
+                json!({"op": "add", "path": "/metadata_admin/repo/a~1b", "value": "owner"}),
+                json!({"op": "remove", "path": "/metadata_admin/repo/c~0d"}),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse() {
+        let yaml = "identities:\n  target:\n    metadata_admin:\n      group: [\"1\"]\n";
+        let desired = Desired::parse(yaml, DocumentFormat::Yaml).unwrap();
+        assert_eq!(
+            desired.identities["target"]["metadata_admin"]["group"],
+            json!(["1"])
+        );
+        assert!(Desired::parse("identities: [", DocumentFormat::Yaml).is_err());
+    }
+
+    #[test]
+    fn test_unknown_mode() {
+        let desired = Desired::parse(
+            r#"{"identities": {"target": {"metadata": {"group": []}}}}"#,
+            DocumentFormat::Json,
+        )
+        .unwrap();
+        let identity = Identity::new(
+            "target".to_owned(),
+            "test".to_owned(),
+            "test".to_owned(),
+            None,
+        );
+        assert!(diff(&identity, &desired.identities["target"]).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Debug},
+    sync::Arc,
+};
+
+use anyhow::{bail, Context, Result};
+use jsonschema::JSONSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use thiserror::Error;
+use utoipa::ToSchema;
+
+use crate::{http::controler::unescape_segment, permission::Input};
+
+///Registered schema of a perm_type.
+#[derive(Deserialize, Serialize, ToSchema, Clone, Debug, Default)]
+pub struct PermSchema {
+    ///Json schema the resource ids must match, they are validated as json strings.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resource: Option<Value>,
+    ///Json schema the values must match.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    ///File holding the json schema of the values, it is loaded with the config when `value` is
+    ///not set.
+    #[serde(default, skip_serializing)]
+    pub file: Option<String>,
+    #[serde(skip)]
+    compiled: Compiled,
+}
+
+///Json schemas of a perm_type compiled once when the config is loaded.
+#[derive(Clone, Default)]
+struct Compiled {
+    resource: Option<Arc<JSONSchema>>,
+    value: Option<Arc<JSONSchema>>,
+    ///Problems found when loading and compiling the schemas, reported by the config validation.
+    problems: Vec<String>,
+}
+
+impl Debug for Compiled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Compiled")
+            .field("problems", &self.problems)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PermSchema {
+    ///Compile the resource and value schemas, the invalid ones are reported by `problems`.
+    pub fn compile(&mut self) {
+        let mut problems = Vec::new();
+        let mut compile = |schema: &Option<Value>, field: &str| {
+            let schema = schema.as_ref()?;
+            match JSONSchema::compile(schema) {
+                Ok(compiled) => Some(Arc::new(compiled)),
+                Err(e) => {
+                    problems.push(format!("invalid {field} schema: {e}"));
+                    None
+                }
+            }
+        };
+        let resource = compile(&self.resource, "resource");
+        let value = compile(&self.value, "value");
+        self.compiled = Compiled {
+            resource,
+            value,
+            problems,
+        };
+    }
+
+    ///Get the problems found when loading and compiling the schemas.
+    pub fn problems(&self) -> &[String] {
+        &self.compiled.problems
+    }
+}
+
+///Schemas of the perm_types by name.
+pub type Schemas = BTreeMap<String, PermSchema>;
+
+///Error raised when a payload does not match the schema of its perm_type.
+#[derive(Error, Debug)]
+#[error("invalid {field} for {perm_type}: {reason}")]
+pub struct SchemaError {
+    pub perm_type: String,
+    pub field: &'static str,
+    pub reason: String,
+}
+
+///Read a json schema file.
+async fn load_file(file: &str) -> Result<Value> {
+    let content = tokio::fs::read_to_string(file)
+        .await
+        .context(format!("cannot read {file}"))?;
+    serde_json::from_str(&content).context(format!("invalid json in {file}"))
+}
+
+///Load the value schemas stored in files and compile all the schemas, the problems found are
+///reported by `problems`.
+pub async fn load_files(schemas: &mut Schemas) {
+    for schema in schemas.values_mut() {
+        let mut problem = None;
+        if let Some(file) = schema.file.as_ref().filter(|_| schema.value.is_none()) {
+            match load_file(file).await {
+                Ok(value) => schema.value = Some(value),
+                Err(e) => problem = Some(format!("{e:#}")),
+            }
+        }
+        schema.compile();
+        schema.compiled.problems.extend(problem);
+    }
+}
+
+///Validate an instance against a compiled json schema, a schema that is set but not compiled
+///is an error.
+fn check(
+    schema: (&Option<Value>, &Option<Arc<JSONSchema>>),
+    instance: &Value,
+    perm_type: &str,
+    field: &'static str,
+) -> Result<()> {
+    let compiled = match schema {
+        (None, _) => return Ok(()),
+        (Some(_), Some(compiled)) => compiled,
+        (Some(_), None) => bail!("the {field} schema of {perm_type} is not compiled"),
+    };
+    if let Err(errors) = compiled.validate(instance) {
+        let reason = errors.map(|e| e.to_string()).collect::<Vec<_>>().join(", ");
+        return Err(SchemaError {
+            perm_type: perm_type.to_owned(),
+            field,
+            reason,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+///Check a resource and its value against the schema registered for the perm_type, the
+///resources of a list have no value.
+fn validate_entry(
+    schemas: &Schemas,
+    perm_type: &str,
+    resource: &Value,
+    value: Option<&Value>,
+) -> Result<()> {
+    let Some(schema) = schemas.get(perm_type) else {
+        return Ok(());
+    };
+    let compiled = &schema.compiled;
+    check(
+        (&schema.resource, &compiled.resource),
+        resource,
+        perm_type,
+        "resource",
+    )?;
+    if let Some(value) = value {
+        check((&schema.value, &compiled.value), value, perm_type, "value")?;
+    }
+    Ok(())
+}
+
+///Check the resource and the value of the payload against the schema registered for its
+///perm_type, the perm_types without schema accept everything.
+pub fn validate(schemas: &Schemas, payload: &Input) -> Result<()> {
+    if !schemas.contains_key(&payload.perm_type) {
+        return Ok(());
+    }
+    let value = serde_json::from_str::<Value>(&payload.value).map_err(|e| SchemaError {
+        perm_type: payload.perm_type.clone(),
+        field: "value",
+        reason: e.to_string(),
+    })?;
+    let resource = Value::String(payload.resource.clone());
+    // a null value adds the resource to a list, there is no value to validate
+    let value = Some(&value).filter(|value| !value.is_null());
+    validate_entry(schemas, &payload.perm_type, &resource, value)
+}
+
+///Check the whole data of a perm_type: the resources and values of a map or the resources of a
+///list.
+fn validate_data(schemas: &Schemas, perm_type: &str, data: &Value) -> Result<()> {
+    if !schemas.contains_key(perm_type) {
+        return Ok(());
+    }
+    match data {
+        Value::Object(map) => map.iter().try_for_each(|(resource, value)| {
+            validate_entry(
+                schemas,
+                perm_type,
+                &Value::String(resource.clone()),
+                Some(value),
+            )
+        }),
+        Value::Array(list) => list
+            .iter()
+            .try_for_each(|resource| validate_entry(schemas, perm_type, resource, None)),
+        _ => Err(SchemaError {
+            perm_type: perm_type.to_owned(),
+            field: "value",
+            reason: "the permissions must be a map or a list".to_owned(),
+        }
+        .into()),
+    }
+}
+
+///Check the data written by the add and replace operations of a json patch on the identity
+///data, `/<root>/<perm_type>/<resource>` paths, against the schemas of their perm_types. The
+///operations on other paths are rejected as their data could not be checked.
+pub fn validate_patch(schemas: &Schemas, patch: &[Value]) -> Result<()> {
+    for operation in patch {
+        if !matches!(operation["op"].as_str(), Some("add" | "replace")) {
+            continue;
+        }
+        let Some(path) = operation["path"]
+            .as_str()
+            .and_then(|path| path.strip_prefix('/'))
+        else {
+            bail!("invalid patch path {}", operation["path"]);
+        };
+        let value = &operation["value"];
+        let segments: Vec<String> = path.split('/').map(unescape_segment).collect();
+        let segments: Vec<&str> = segments.iter().map(String::as_str).collect();
+        match segments[..] {
+            [_] => {
+                for (perm_type, data) in value.as_object().into_iter().flatten() {
+                    validate_data(schemas, perm_type, data)?;
+                }
+            }
+            [_, perm_type] => validate_data(schemas, perm_type, value)?,
+            [_, perm_type, "-"] => validate_entry(schemas, perm_type, value, None)?,
+            [_, perm_type, resource] => {
+                let resource = Value::String(resource.to_owned());
+                validate_entry(schemas, perm_type, &resource, Some(value))?
+            }
+            _ => bail!("unexpected patch path /{path}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_schema {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_validate() {
+        let mut schema = PermSchema {
+            resource: Some(json!({"type": "string", "pattern": "^[0-9]+$"})),
+            value: Some(json!({"enum": ["owner", "viewer"]})),
+            ..Default::default()
+        };
+        schema.compile();
+        assert!(schema.problems().is_empty());
+        let schemas = Schemas::from([("project".to_owned(), schema)]);
+        let mut payload = Input {
+            perm_type: "project".to_owned(),
+            resource: "222".to_owned(),
+            value: "\"owner\"".to_owned(),
+            ..Default::default()
+        };
+        validate(&schemas, &payload).unwrap();
+        payload.value = "\"admin\"".to_owned();
+        let e = validate(&schemas, &payload).unwrap_err();
+        assert_eq!(e.downcast_ref::<SchemaError>().unwrap().field, "value");
+        payload.value = "\"owner\"".to_owned();
+        payload.resource = "abc".to_owned();
+        let e = validate(&schemas, &payload).unwrap_err();
+        assert_eq!(e.downcast_ref::<SchemaError>().unwrap().field, "resource");
+        payload.perm_type = "group".to_owned();
+        validate(&schemas, &payload).unwrap();
+
+        let patch = vec![
+            json!({"op": "add", "path": "/metadata_admin", "value": {"project": {"1": "owner"}}}),
+            json!({"op": "add", "path": "/metadata_admin/project", "value": {"2": "viewer"}}),
+            json!({"op": "replace", "path": "/metadata_admin/project/3", "value": "owner"}),
+            json!({"op": "remove", "path": "/metadata_admin/project/abc"}),
+        ];
+        validate_patch(&schemas, &patch).unwrap();
+        let invalid = [
+            json!({"op": "add", "path": "/traits", "value": {"project": {"1": "admin"}}}),
+            json!({"op": "add", "path": "/traits/project", "value": ["abc"]}),
+            json!({"op": "add", "path": "/traits/project/-", "value": "abc"}),
+            json!({"op": "add", "path": "/traits/project/abc", "value": "owner"}),
+        ];
+        for operation in invalid {
+            let e = validate_patch(&schemas, &[operation.clone()]).unwrap_err();
+            assert!(e.downcast_ref::<SchemaError>().is_some(), "{operation}");
+        }
+        // the escaped resources are checked, the paths that can not be checked are rejected
+        let operation = json!({"op": "add", "path": "/traits/project/a~1b", "value": "owner"});
+        let e = validate_patch(&schemas, &[operation]).unwrap_err();
+        assert!(e.downcast_ref::<SchemaError>().is_some());
+        let operation = json!({"op": "add", "path": "/traits/project/a/b", "value": "owner"});
+        let e = validate_patch(&schemas, &[operation]).unwrap_err();
+        assert!(e.downcast_ref::<SchemaError>().is_none());
+    }
+
+    #[test]
+    fn test_compile() {
+        let mut schema = PermSchema {
+            value: Some(json!({"type": "unknown"})),
+            ..Default::default()
+        };
+        schema.compile();
+        assert_eq!(schema.problems().len(), 1);
+        let schemas = Schemas::from([("project".to_owned(), schema)]);
+        let payload = Input {
+            perm_type: "project".to_owned(),
+            value: "\"owner\"".to_owned(),
+            ..Default::default()
+        };
+        let e = validate(&schemas, &payload).unwrap_err();
+        assert!(e.downcast_ref::<SchemaError>().is_none());
+    }
+}
@@ -0,0 +1,144 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{sync::watch, time::Instant};
+use tracing::info;
+
+use crate::{config::Shutdown, handler::shutdown_signal};
+
+///Background tasks the shutdown waits for, like the purge jobs and the queued event deliveries.
+#[derive(Clone, Debug)]
+pub struct Tasks(Arc<watch::Sender<usize>>);
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Tasks(Arc::new(watch::Sender::new(0)))
+    }
+}
+
+impl Tasks {
+    ///Register a running task, it is done when the guard is dropped.
+    pub fn track(&self) -> TaskGuard {
+        self.0.send_modify(|running| *running += 1);
+        TaskGuard(self.0.clone())
+    }
+
+    ///Wait until no task is running.
+    pub async fn idle(&self) {
+        let mut receiver = self.0.subscribe();
+        // the sender is kept by self
+        let _ = receiver.wait_for(|running| *running == 0).await;
+    }
+}
+
+///Guard of a running background task.
+#[derive(Debug)]
+pub struct TaskGuard(Arc<watch::Sender<usize>>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|running| *running -= 1);
+    }
+}
+
+///Coordinate the shutdown of the servers: on the signal the readiness fails first, then after
+///the pre stop delay all the servers are drained with the same deadline, the background tasks
+///are given until the same deadline.
+#[derive(Clone, Debug)]
+pub struct ShutdownCoordinator {
+    draining: Arc<AtomicBool>,
+    ///Deadline of the drain, set once the servers must be drained.
+    deadline: Arc<watch::Sender<Option<Instant>>>,
+    pub tasks: Tasks,
+}
+
+impl Default for ShutdownCoordinator {
+    fn default() -> Self {
+        ShutdownCoordinator {
+            draining: Arc::default(),
+            deadline: Arc::new(watch::Sender::new(None)),
+            tasks: Tasks::default(),
+        }
+    }
+}
+
+impl ShutdownCoordinator {
+    ///Tell if the shutdown has started, the service should not be reported ready.
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    ///Wait for the shutdown signal then run the shutdown sequence.
+    pub async fn run(self, config: Shutdown) {
+        shutdown_signal().await;
+        self.shutdown(config).await;
+    }
+
+    ///Fail the readiness, wait the pre stop delay and start draining the servers.
+    pub async fn shutdown(&self, config: Shutdown) {
+        self.draining.store(true, Ordering::Relaxed);
+        info!("readiness failing, draining in {}s", config.pre_stop);
+        tokio::time::sleep(Duration::from_secs(config.pre_stop)).await;
+        info!("draining the servers for at most {}s", config.timeout);
+        self.deadline
+            .send_replace(Some(Instant::now() + Duration::from_secs(config.timeout)));
+    }
+
+    ///Wait until the servers must be drained and return the drain deadline.
+    pub async fn drain_deadline(&self) -> Instant {
+        let mut receiver = self.deadline.subscribe();
+        let deadline = match receiver.wait_for(Option::is_some).await {
+            Ok(deadline) => *deadline,
+            // the sender is kept by self
+            Err(_) => None,
+        };
+        deadline.unwrap_or_else(Instant::now)
+    }
+
+    ///Wait until the drain deadline is reached.
+    pub async fn deadline(&self) {
+        tokio::time::sleep_until(self.drain_deadline().await).await;
+    }
+}
+
+#[cfg(test)]
+mod test_shutdown {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shutdown() {
+        let coordinator = ShutdownCoordinator::default();
+        assert!(!coordinator.is_draining());
+        let config = Shutdown {
+            pre_stop: 0,
+            timeout: 1,
+        };
+        let waiting = tokio::spawn({
+            let coordinator = coordinator.clone();
+            async move { coordinator.drain_deadline().await }
+        });
+        coordinator.shutdown(config).await;
+        assert!(coordinator.is_draining());
+        assert!(waiting.await.unwrap() > Instant::now());
+    }
+
+    #[tokio::test]
+    async fn test_tasks() {
+        let tasks = Tasks::default();
+        tasks.idle().await;
+        let guard = tasks.track();
+        let idle = tokio::spawn({
+            let tasks = tasks.clone();
+            async move { tasks.idle().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!idle.is_finished());
+        drop(guard);
+        idle.await.unwrap();
+    }
+}
@@ -0,0 +1,72 @@
+use axum::extract::FromRef;
+
+use crate::{
+    config::{ReloadStatus, SharedConfig},
+    event::EventPublisher,
+    job::JobRegistry,
+    limit::RateLimiter,
+    shutdown::ShutdownCoordinator,
+};
+
+///Representation of the state shared by the http and grpc routers.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: SharedConfig,
+    pub jobs: JobRegistry,
+    pub events: EventPublisher,
+    pub limiter: RateLimiter,
+    pub shutdown: ShutdownCoordinator,
+    pub reload: ReloadStatus,
+}
+
+impl AppState {
+    pub fn new(config: SharedConfig) -> Self {
+        let reload = ReloadStatus::default();
+        reload.loaded();
+        let shutdown = ShutdownCoordinator::default();
+        AppState {
+            events: EventPublisher::new(config.clone(), shutdown.tasks.clone()),
+            config,
+            jobs: JobRegistry::default(),
+            limiter: RateLimiter::default(),
+            shutdown,
+            reload,
+        }
+    }
+}
+
+impl FromRef<AppState> for SharedConfig {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for JobRegistry {
+    fn from_ref(state: &AppState) -> Self {
+        state.jobs.clone()
+    }
+}
+
+impl FromRef<AppState> for EventPublisher {
+    fn from_ref(state: &AppState) -> Self {
+        state.events.clone()
+    }
+}
+
+impl FromRef<AppState> for RateLimiter {
+    fn from_ref(state: &AppState) -> Self {
+        state.limiter.clone()
+    }
+}
+
+impl FromRef<AppState> for ShutdownCoordinator {
+    fn from_ref(state: &AppState) -> Self {
+        state.shutdown.clone()
+    }
+}
+
+impl FromRef<AppState> for ReloadStatus {
+    fn from_ref(state: &AppState) -> Self {
+        state.reload.clone()
+    }
+}
@@ -0,0 +1,190 @@
+use std::{
+    fs::{DirBuilder, Permissions},
+    io,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use axum::{extract::Request, Router};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+};
+use tokio::{
+    net::UnixListener,
+    task::{JoinHandle, JoinSet},
+};
+use tower::ServiceExt;
+use tower_http::add_extension::AddExtension;
+use tracing::{error, info};
+
+use crate::{limit::ClientIdentity, shutdown::ShutdownCoordinator};
+
+///Socket file of a listener, it is removed when dropped once the server is stopped.
+#[derive(Debug)]
+pub struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        match std::fs::remove_file(&self.0) {
+            Ok(()) => info!("{} removed", self.0.display()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => error!("cannot remove {}: {e}", self.0.display()),
+        }
+    }
+}
+
+///Bind a unix socket, the stale socket of a previous run is replaced, and restrict its access
+///with the file `mode`. The socket is bound in a private directory and moved to its path once
+///restricted, so it is never reachable with the default permissions.
+pub fn bind(path: &Path, mode: u32) -> Result<(UnixListener, SocketFile)> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+        Ok(_) => bail!("{} exists and is not a socket", path.display()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e.into()),
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let private = dir.join(format!(".iam-{}", std::process::id()));
+    // a directory left by a crashed run
+    let _ = std::fs::remove_dir_all(&private);
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .with_context(|| format!("cannot create {}", private.display()))?;
+    let bound = (|| {
+        let socket = private.join("socket");
+        let listener = UnixListener::bind(&socket)?;
+        std::fs::set_permissions(&socket, Permissions::from_mode(mode))?;
+        std::fs::rename(&socket, path)?;
+        anyhow::Ok(listener)
+    })();
+    if let Err(e) = std::fs::remove_dir_all(&private) {
+        error!("cannot remove {}: {e}", private.display());
+    }
+    let listener = bound.with_context(|| format!("cannot bind {}", path.display()))?;
+    Ok((listener, SocketFile(path.to_path_buf())))
+}
+
+///Identity of the peer of a unix socket connection given by its user id.
+pub fn peer_identity(uid: Option<u32>) -> ClientIdentity {
+    ClientIdentity(uid.map(|uid| format!("uid:{uid}")))
+}
+
+///Serve a router on a unix socket until the servers are drained, the requests are identified by
+///the user id of the peer. Once draining the connections are shut down gracefully and the server
+///only returns when they are all closed.
+pub fn serve(
+    listener: UnixListener,
+    router: Router,
+    shutdown: ShutdownCoordinator,
+) -> JoinHandle<Result<(), io::Error>> {
+    tokio::spawn(async move {
+        let mut connections = JoinSet::new();
+        loop {
+            let (stream, _) = tokio::select! {
+                accepted = listener.accept() => accepted?,
+                // the closed connections are reaped
+                Some(_) = connections.join_next() => continue,
+                _ = shutdown.drain_deadline() => break,
+            };
+            let uid = stream.peer_cred().ok().map(|cred| cred.uid());
+            let service = AddExtension::new(router.clone(), peer_identity(uid));
+            let service = hyper::service::service_fn(move |request: Request<Incoming>| {
+                service.clone().oneshot(request)
+            });
+            let shutdown = shutdown.clone();
+            connections.spawn(async move {
+                let builder = Builder::new(TokioExecutor::new());
+                let connection = builder.serve_connection(TokioIo::new(stream), service);
+                tokio::pin!(connection);
+                let served = tokio::select! {
+                    served = connection.as_mut() => served,
+                    _ = shutdown.drain_deadline() => {
+                        connection.as_mut().graceful_shutdown();
+                        connection.await
+                    }
+                };
+                if let Err(e) = served {
+                    error!("unix socket connection failed: {e}");
+                }
+            });
+        }
+        info!("closing {} unix socket connections", connections.len());
+        while connections.join_next().await.is_some() {}
+        info!("unix socket server drained");
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod test_unix {
+    use std::time::Duration;
+
+    use axum::routing::get;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::config::Shutdown;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("iam.sock");
+        let (listener, socket) = bind(&path, 0o600).unwrap();
+        let metadata = std::fs::metadata(&path).unwrap();
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        tokio::net::UnixStream::connect(&path).await.unwrap();
+        listener.accept().await.unwrap();
+        // the socket of a previous run is replaced
+        std::mem::forget(socket);
+        let (_, socket) = bind(&path, 0o600).unwrap();
+        // only the socket is left in the directory
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+        drop(socket);
+        assert!(!path.exists());
+
+        let file = dir.path().join("iam.txt");
+        std::fs::write(&file, "").unwrap();
+        assert!(bind(&file, 0o600).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_drain() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("iam.sock");
+        let (listener, _socket) = bind(&path, 0o600).unwrap();
+        let router = Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "done"
+            }),
+        );
+        let coordinator = ShutdownCoordinator::default();
+        let server = serve(listener, router, coordinator.clone());
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nhost: iam\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let config = Shutdown {
+            pre_stop: 0,
+            timeout: 5,
+        };
+        coordinator.shutdown(config).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the request in progress is served before the server returns
+        assert!(!server.is_finished());
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.ends_with("done"));
+        server.await.unwrap().unwrap();
+    }
+}